@@ -1,75 +1,37 @@
-use std::{path::Path, sync::Arc, process::{ExitStatus, Stdio}};
+use std::{path::Path, collections::{HashMap, HashSet, VecDeque}, sync::{Arc, atomic::{AtomicU64, Ordering}}, time::{Duration, Instant}, process::Stdio};
 
-use nix::{sys::signal::{self, Signal}, unistd::Pid};
-use tokio::{fs, task::JoinHandle, sync::Mutex, process::Command, io::{BufReader, AsyncBufReadExt}};
-use unicom_lib::error::UnicomError;
+use futures::{future::BoxFuture, FutureExt};
+use tokio::{fs, sync::{Mutex, watch}, process::Command, time::{sleep, timeout}, io::{BufReader, AsyncBufReadExt}};
+use nix::unistd::{User, Group, Uid, Gid};
+use unicom_lib::{error::{UnicomError, UnicomErrorKind}, node::NodeConfig};
 
-use crate::LOGGER;
+use crate::{LOGGER, scheduler::JobConfig, log::parse::LogParseConfig};
 
-pub struct AppProcess{
-    name: String,
-    pid : u32,
-    handle: JoinHandle<Result<ExitStatus, UnicomError>>,
-}
-
-impl AppProcess {
-    pub fn new(cmd: &mut Command, name: String) -> Result<AppProcess, UnicomError>{
-        // let mut child = cmd.spawn()?;
-        let mut child = cmd.stdin(Stdio::null())
-        .stdout(Stdio::piped())
-        .stderr(Stdio::piped()).spawn()?;
-
-        let pid = child.id().unwrap();
-        let stdout = child.stdout.take().expect("child did not have a handle to stdout");
-        let stderr = child.stderr.take().expect("child did not have a handle to stderr");
-
-        let name1 = name.clone();
-        let name2 = name.clone();
-
-        tokio::spawn(async move{
-            
-            let mut reader = BufReader::new(stdout).lines();
-            while let Ok(Some(line)) = reader.next_line().await {
-                LOGGER.app_stdout(&name1, line).await;
-            }
-        });
-
-        tokio::spawn(async move{
-            let mut reader = BufReader::new(stderr).lines();
-            while let Ok(Some(line)) = reader.next_line().await {
-                LOGGER.app_stderr(&name2, line).await;
-            }
-        });
-
-        let handle= tokio::spawn( async move {
-            let status =  child.wait().await?;
-            Ok(status)
-        });
-
-        Ok(AppProcess{
-            pid,
-            handle,
-            name
-        })
-    }
-
-    pub async fn stop(&mut self) -> Result<(), UnicomError>{
-        println!("{}", self.pid);
-        signal::kill(Pid::from_raw(self.pid as i32), Signal::SIGTERM).unwrap();
-        let fut = &mut self.handle;
-        let status = fut.await.unwrap()?;
-        LOGGER.app_stdout(&self.name, format!("stoped with code {}", status)).await;
-        Ok(())
-    }
-}
+use super::{process::{AppProcess, ExitInfo, wait_exit}, watch::AppWatcher, limits::LimitsConfig, health::{HealthConfig, HealthCheck}, demand::OnDemandConfig};
 
 #[derive(Debug, Clone, PartialEq, Serialize)]
 pub enum AppState{
     Waiting,
     Started,
     Running,
+    // running but failing its health checks
+    Unhealthy,
     Zombie,
     Stoped,
+    Failed,
+    LimitExceeded,
+    Disabled,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AppStatus{
+    name: String,
+    state: AppState,
+    after: Vec<String>,
+    pub waiting_for: Vec<String>,
+    last_exit: Option<ExitInfo>,
+    restarts: usize,
+    health: VecDeque<HealthCheck>,
 }
 
 
@@ -80,11 +42,23 @@ pub struct App{
     pub auto_reload: Arc<Mutex<bool>>,
     pub state: Mutex<AppState>,
     stream: String,
-
+    // names of the connected nodes, shared with the controller
+    nodes: Arc<Mutex<HashSet<String>>>,
+    // bumped on every start and stop, a supervisor from an older generation gives up
+    generation: AtomicU64,
+    last_exit: Mutex<Option<ExitInfo>>,
+    restarts: Mutex<Vec<Instant>>,
+    watcher: Mutex<Option<AppWatcher>>,
+    health: Mutex<VecDeque<HealthCheck>>,
+    // routes of an on demand app, registered while its node is not connected
+    pub manifest: Option<NodeConfig>,
+    last_used: Mutex<Instant>,
+    // held by start and stop, a second start waits for the first one and finds the app started
+    start_lock: Mutex<()>,
 }
 
 impl App{
-    pub fn new(dir: &str, config: AppConfig, stream: &str) -> App{
+    pub fn new(dir: &str, config: AppConfig, manifest: Option<NodeConfig>, stream: &str, nodes: Arc<Mutex<HashSet<String>>>) -> App{
         let auto_reload = Arc::new(Mutex::new(config.auto_reload.unwrap_or(false)));
         App{
             config,
@@ -93,6 +67,15 @@ impl App{
             auto_reload,
             state: Mutex::new(AppState::Waiting),
             stream: stream.to_string(),
+            nodes,
+            generation: AtomicU64::new(0),
+            last_exit: Mutex::new(None),
+            restarts: Mutex::new(Vec::new()),
+            watcher: Mutex::new(None),
+            health: Mutex::new(VecDeque::new()),
+            manifest,
+            last_used: Mutex::new(Instant::now()),
+            start_lock: Mutex::new(()),
         }
     }
 
@@ -101,55 +84,378 @@ impl App{
         state.clone()
     }
 
+    pub async fn status(&self) -> AppStatus{
+        AppStatus{
+            name: self.config.name.clone(),
+            state: self.get_state().await,
+            after: self.config.after.clone(),
+            waiting_for: Vec::new(),
+            last_exit: self.last_exit.lock().await.clone(),
+            restarts: self.restarts.lock().await.len(),
+            health: self.health.lock().await.clone(),
+        }
+    }
+
+    pub async fn watch(&self) -> Result<(), UnicomError>{
+        if !self.config.auto_reload.unwrap_or(false){
+            return Ok(())
+        }
+        let debounce = Duration::from_millis(self.config.auto_reload_debounce.unwrap_or(500));
+        *self.watcher.lock().await = Some(AppWatcher::new(&self.config.name, &self.dir, self.auto_reload.clone(), 
+                                                            &self.config.auto_reload_ignore, debounce)?);
+        Ok(())
+    }
+
+    // only set_enabled leaves Disabled, stop, the supervisor and the health checks keep it
+    async fn set_state(&self, state: AppState) {
+        let mut current = self.state.lock().await;
+        if *current != AppState::Disabled{
+            *current = state;
+        }
+    }
+
     pub async fn set_running(&self) {
-        *self.state.lock().await = AppState::Running;
+        self.set_state(AppState::Running).await;
     }
 
     pub async fn set_zombie(&self) {
-        *self.state.lock().await = AppState::Zombie;
+        self.set_state(AppState::Zombie).await;
+    }
+
+    pub async fn set_disabled(&self) {
+        *self.state.lock().await = AppState::Disabled;
+    }
+
+    pub async fn set_enabled(&self) {
+        let mut state = self.state.lock().await;
+        if *state == AppState::Disabled{
+            *state = AppState::Stoped;
+        }
+    }
+
+    pub async fn set_failed(&self) {
+        self.set_state(AppState::Failed).await;
     }
 
-    pub async fn start(&self) -> Result<(), UnicomError>{
+    // false when the app is already started
+    async fn can_start(&self) -> Result<bool, UnicomError>{
         match *self.state.lock().await {
-            AppState::Started|AppState::Running|AppState::Zombie => return Ok(()),
-            _ => (),
+            AppState::Started|AppState::Running|AppState::Unhealthy|AppState::Zombie => Ok(false),
+            AppState::Disabled => Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("app {} is disabled", self.config.name))),
+            _ => Ok(true),
+        }
+    }
+
+    // waits up to after_timeout for the `after` nodes to be connected
+    async fn wait_dependencies(&self) -> Result<(), UnicomError>{
+        let deadline = Instant::now() + Duration::from_secs(self.config.after_timeout);
+        loop{
+            let nodes = self.nodes.lock().await;
+            if self.config.after.iter().all(|name| nodes.contains(name)){
+                return Ok(())
+            }
+            drop(nodes);
+            if Instant::now() >= deadline{
+                return Err(UnicomError::new(UnicomErrorKind::NotFound, 
+                            &format!("app {} dependencies {:?} not ready after {}s", self.config.name, self.config.after, self.config.after_timeout)))
+            }
+            sleep(Duration::from_millis(200)).await;
+        }
+    }
+
+    pub async fn start(self: &Arc<Self>) -> Result<(), UnicomError>{
+        if !self.can_start().await?{
+            return Ok(())
+        }
+        let generation = self.generation.load(Ordering::SeqCst);
+        if let Err(e) = self.wait_dependencies().await{
+            self.set_state(AppState::Failed).await;
+            return Err(e)
+        }
+
+        let _start = self.start_lock.lock().await;
+        if !self.can_start().await?{
+            return Ok(())
+        }
+        if self.generation.load(Ordering::SeqCst) != generation{
+            return Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("app {} stoped while waiting for its dependencies", self.config.name)))
+        }
+
+        let mut cmd = self.config.kind.command(&self.config.name, &self.dir, &self.stream);
+        self.config.prepare(&mut cmd, &self.dir).await?;
+        let process = AppProcess::new(&mut cmd, self.config.name.clone(), &self.config.limits).await?;
+        let generation = self.generation.fetch_add(1, Ordering::SeqCst) + 1;
+        let exit = process.exit();
+        self.set_state(AppState::Started).await;
+        *self.connection.lock().await = Some(process);
+        tokio::spawn(self.clone().supervise(exit, generation));
+        if let Some(health) = &self.config.health{
+            tokio::spawn(self.clone().check_health(health.clone(), generation));
+        }
+        if let Some(on_demand) = &self.config.on_demand{
+            *self.last_used.lock().await = Instant::now();
+            tokio::spawn(self.clone().stop_idle(Duration::from_secs(on_demand.idle_timeout), generation));
+        }
+        Ok(())
+    }
+
+    pub async fn touch(&self){
+        *self.last_used.lock().await = Instant::now();
+    }
+
+    // starts an on demand app if needed and waits for its node
+    pub async fn demand(self: &Arc<Self>) -> Result<(), UnicomError>{
+        let start_timeout = match &self.config.on_demand{
+            Some(on_demand) => on_demand.start_timeout,
+            None => return Err(UnicomError::new(UnicomErrorKind::NotFound, &format!("app {} is not on demand", self.config.name))),
         };
+        self.touch().await;
+        if self.get_state().await == AppState::Running{
+            return Ok(())
+        }
+        LOGGER.app_stdout(&self.config.name, "started on demand".to_string()).await;
+        self.start().await?;
+        if !self.wait_running(Duration::from_secs(start_timeout)).await{
+            return Err(UnicomError::new(UnicomErrorKind::NotFound, 
+                        &format!("app {} not running {}s after its on demand start", self.config.name, start_timeout)))
+        }
+        Ok(())
+    }
 
-        let mut cmd = match &self.config.kind {
-            AppType::Python { venv } => {
-                let mut cmd = Command::new("unicom-python");
-                cmd.arg(&self.dir);
-                cmd.arg(&self.stream);
-                if let Some(venv) = venv{
-                    cmd.arg(venv);
+    fn stop_idle(self: Arc<Self>, idle_timeout: Duration, generation: u64) -> BoxFuture<'static, ()>{
+        async move {
+            loop{
+                sleep(idle_timeout.min(Duration::from_secs(30))).await;
+                if self.generation.load(Ordering::SeqCst) != generation{
+                    return
+                }
+                if self.last_used.lock().await.elapsed() < idle_timeout{
+                    continue
+                }
+                LOGGER.app_stdout(&self.config.name, format!("idle for {}s, stopping", idle_timeout.as_secs())).await;
+                if let Err(e) = self.stop().await{
+                    LOGGER.error(&format!("stop idle app {}", self.config.name), e).await;
+                }
+                return
+            }
+        }.boxed()
+    }
+
+    // runs the health checks of one generation, an unhealthy app is restarted according to its restart policy
+    fn check_health(self: Arc<Self>, health: HealthConfig, generation: u64) -> BoxFuture<'static, ()>{
+        async move {
+            let mut failures = 0;
+            let mut successes = 0;
+            loop{
+                sleep(Duration::from_secs(health.interval)).await;
+                if self.generation.load(Ordering::SeqCst) != generation{
+                    return
+                }
+                // nothing to check until the node is connected
+                let state = self.get_state().await;
+                if state != AppState::Running && state != AppState::Unhealthy{
+                    continue
+                }
+
+                let check = health.check(&self.config.name).await;
+                if check.healthy{
+                    failures = 0;
+                    successes += 1;
+                }
+                else{
+                    successes = 0;
+                    failures += 1;
+                }
+                let mut history = self.health.lock().await;
+                history.push_back(check.clone());
+                while history.len() > health.history{
+                    history.pop_front();
+                }
+                drop(history);
+                if self.generation.load(Ordering::SeqCst) != generation{
+                    return
                 }
 
+                if state == AppState::Unhealthy && successes >= health.healthy_threshold{
+                    LOGGER.app_stdout(&self.config.name, "[HEALTH] healthy again".to_string()).await;
+                    self.set_state(AppState::Running).await;
+                    continue
+                }
+                if state != AppState::Running || failures < health.unhealthy_threshold{
+                    continue
+                }
+
+                let error = check.error.unwrap_or_default();
+                LOGGER.app_stderr(&self.config.name, format!("[HEALTH] unhealthy after {} failed checks: {}", failures, error)).await;
+                self.set_state(AppState::Unhealthy).await;
+                if self.config.restart.policy == RestartPolicy::Never{
+                    continue
+                }
+
+                let delay = match self.register_restart().await{
+                    Some(delay) => delay,
+                    None => {
+                        LOGGER.app_stderr(&self.config.name, format!("[HEALTH] restart limit reached ({} in {}s)", 
+                                            self.config.restart.limit, self.config.restart.window)).await;
+                        if let Err(e) = self.stop().await{
+                            LOGGER.error(&format!("stop unhealthy app {}", self.config.name), e).await;
+                        }
+                        self.set_state(AppState::Failed).await;
+                        return
+                    },
+                };
+                LOGGER.app_stderr(&self.config.name, format!("[HEALTH] restarting in {:.1}s", delay.as_secs_f32())).await;
+                if let Err(e) = self.stop().await{
+                    LOGGER.error(&format!("stop unhealthy app {}", self.config.name), e).await;
+                }
+                let stoped = self.generation.load(Ordering::SeqCst);
+                self.set_state(AppState::Waiting).await;
+                sleep(delay).await;
+                if self.generation.load(Ordering::SeqCst) != stoped{
+                    return
+                }
+                if let Err(e) = self.start().await{
+                    LOGGER.error(&format!("restart app {}", self.config.name), e).await;
+                    self.set_state(AppState::Failed).await;
+                }
+                return
+            }
+        }.boxed()
+    }
+
+    fn supervise(self: Arc<Self>, mut exit: watch::Receiver<Option<ExitInfo>>, generation: u64) -> BoxFuture<'static, ()>{
+        async move {
+            let info = wait_exit(&mut exit).await;
+            if self.generation.load(Ordering::SeqCst) != generation{
+                return
+            }
+            *self.last_exit.lock().await = Some(info.clone());
+            *self.connection.lock().await = None;
+
+            let restart = match self.config.restart.policy{
+                RestartPolicy::Never => false,
+                RestartPolicy::OnFailure => !info.success(),
+                RestartPolicy::Always => true,
+            };
+
+            if let Some(reason) = &info.reason{
+                LOGGER.app_stderr(&self.config.name, format!("[LIMIT] {}", reason)).await;
+            }
+
+            if !restart{
+                LOGGER.app_stderr(&self.config.name, format!("exited with {}", info)).await;
+                self.set_state(match (info.success(), info.reason.is_some()){
+                    (_, true) => AppState::LimitExceeded,
+                    (true, false) => AppState::Stoped,
+                    (false, false) => AppState::Failed,
+                }).await;
+                return
+            }
+
+            let delay = match self.register_restart().await{
+                Some(delay) => delay,
+                None => {
+                    LOGGER.app_stderr(&self.config.name, format!("exited with {}, restart limit reached ({} in {}s)", 
+                                        info, self.config.restart.limit, self.config.restart.window)).await;
+                    self.set_state(AppState::Failed).await;
+                    return
+                },
+            };
+
+            LOGGER.app_stderr(&self.config.name, format!("exited with {}, restarting in {:.1}s", info, delay.as_secs_f32())).await;
+            self.set_state(AppState::Waiting).await;
+            sleep(delay).await;
+            if self.generation.load(Ordering::SeqCst) != generation{
+                return
+            }
+            if let Err(e) = self.start().await{
+                LOGGER.error(&format!("restart app {}", self.config.name), e).await;
+                self.set_state(AppState::Failed).await;
+            }
+        }.boxed()
+    }
+
+    async fn register_restart(&self) -> Option<Duration>{
+        self.config.restart.backoff(&mut *self.restarts.lock().await, Instant::now())
+    }
+
+    // runs the update hook with its output in the app log, fails on timeout or a non zero exit
+    pub async fn run_update_hook(&self) -> Result<(), UnicomError>{
+        let hook_name = self.config.update.hook.as_deref().unwrap_or("unicom-app-update");
+        let mut cmd = match &self.config.update.hook{
+            Some(hook) => Command::new(Path::new(&self.dir).join(hook)),
+            None => {
+                let mut cmd = Command::new("unicom-app-update");
+                cmd.arg(&self.dir);
                 cmd
             },
         };
-        *self.state.lock().await = AppState::Started;
-        *self.connection.lock().await = Some(AppProcess::new(&mut cmd, self.config.name.clone())?);
-        Ok(())
-    }
+        self.config.prepare(&mut cmd, &self.dir).await?;
+        cmd.current_dir(&self.dir);
 
-    pub async fn update(&self) -> Result<(), UnicomError>{
-        let mut child = Command::new("unicom-app-update")
-        .arg(&self.dir)
-        .spawn()?;
+        let mut child = cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .kill_on_drop(true).spawn()?;
 
-        child.wait().await?;
+        let stdout = child.stdout.take().expect("child did not have a handle to stdout");
+        let stderr = child.stderr.take().expect("child did not have a handle to stderr");
+        let name1 = self.config.name.clone();
+        let name2 = self.config.name.clone();
+        tokio::spawn(async move{
+            let mut reader = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = reader.next_line().await {
+                LOGGER.app_stdout(&name1, format!("[UPDATE] {}", line)).await;
+            }
+        });
+        tokio::spawn(async move{
+            let mut reader = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = reader.next_line().await {
+                LOGGER.app_stderr(&name2, format!("[UPDATE] {}", line)).await;
+            }
+        });
 
+        let status = match timeout(Duration::from_secs(self.config.update.timeout), child.wait()).await{
+            Ok(status) => status?,
+            Err(_) => {
+                child.kill().await?;
+                return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, 
+                            &format!("app {} update hook {} timed out after {}s", self.config.name, hook_name, self.config.update.timeout)))
+            },
+        };
+        if !status.success(){
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, 
+                        &format!("app {} update hook {} failed with exit status {}", self.config.name, hook_name, ExitInfo::from(status))))
+        }
         Ok(())
     }
 
+    // waits for the app node to register, false on timeout or once the app is stoped or failed
+    pub async fn wait_running(&self, wait: Duration) -> bool{
+        let deadline = Instant::now() + wait;
+        loop{
+            match self.get_state().await{
+                AppState::Running => return true,
+                AppState::Waiting|AppState::Started|AppState::Zombie => (),
+                _ => return false,
+            }
+            if Instant::now() >= deadline{
+                return false
+            }
+            sleep(Duration::from_millis(200)).await;
+        }
+    }
+
     pub async fn stop(&self) -> Result<(), UnicomError>{
+        let _start = self.start_lock.lock().await;
+        self.generation.fetch_add(1, Ordering::SeqCst);
         let mut t = self.connection.lock().await;
         if let Some(connection) = &mut *t{
-            connection.stop().await?;
+            connection.stop(Duration::from_secs_f32(self.config.stop_timeout)).await?;
             *t = None;
         }
         drop(t);
-        *self.state.lock().await = AppState::Stoped;
+        self.set_state(AppState::Stoped).await;
         Ok(())
     }
 }
@@ -158,6 +464,69 @@ impl App{
 pub enum AppType{
     Python{
         venv: Option<String>,
+    },
+    // any program, `{app_dir}`, `{stream}` and `{name}` are replaced in args
+    Command{
+        program: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    // executable relative to the app directory, called with <app_dir> <stream> [args]
+    Native{
+        executable: String,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+    // node.js entry point relative to the app directory, called with <app_dir> <stream> [args]
+    Node{
+        entry: String,
+        node: Option<String>,
+        #[serde(default)]
+        args: Vec<String>,
+    },
+}
+
+impl AppType{
+    // every kind also gets UNICOM_APP_NAME, UNICOM_APP_DIR and UNICOM_STREAM in its environment
+    pub fn command(&self, name: &str, dir: &str, stream: &str) -> Command{
+        let mut cmd = match self {
+            AppType::Python { venv } => {
+                let mut cmd = Command::new("unicom-python");
+                cmd.arg(dir);
+                cmd.arg(stream);
+                if let Some(venv) = venv{
+                    cmd.arg(venv);
+                }
+
+                cmd
+            },
+            AppType::Command { program, args } => {
+                let mut cmd = Command::new(program);
+                for arg in args{
+                    cmd.arg(arg.replace("{app_dir}", dir).replace("{stream}", stream).replace("{name}", name));
+                }
+                cmd
+            },
+            AppType::Native { executable, args } => {
+                let mut cmd = Command::new(Path::new(dir).join(executable));
+                cmd.arg(dir);
+                cmd.arg(stream);
+                cmd.args(args);
+                cmd
+            },
+            AppType::Node { entry, node, args } => {
+                let mut cmd = Command::new(node.as_deref().unwrap_or("node"));
+                cmd.arg(Path::new(dir).join(entry));
+                cmd.arg(dir);
+                cmd.arg(stream);
+                cmd.args(args);
+                cmd
+            },
+        };
+        cmd.env("UNICOM_APP_NAME", name);
+        cmd.env("UNICOM_APP_DIR", dir);
+        cmd.env("UNICOM_STREAM", stream);
+        cmd
     }
 }
 
@@ -165,14 +534,314 @@ pub enum AppType{
 pub struct AppConfig{
     pub name: String, 
     pub kind: AppType,
-    pub after: Option<String>,
-    pub auto_reload: Option<bool>
+    // names of the apps or nodes which must be connected before starting
+    #[serde(default, deserialize_with = "string_or_list")]
+    pub after: Vec<String>,
+    // seconds to wait for `after` before giving up
+    #[serde(default = "default_after_timeout")]
+    pub after_timeout: u64,
+    pub auto_reload: Option<bool>,
+    // glob patterns added to the default ignore list (.git, __pycache__, *.pyc, ...)
+    #[serde(default)]
+    pub auto_reload_ignore: Vec<String>,
+    // milliseconds without change before reloading
+    pub auto_reload_debounce: Option<u64>,
+    #[serde(default)]
+    pub restart: RestartConfig,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+    // KEY=VALUE files relative to the app directory, `env` wins over them
+    #[serde(default)]
+    pub env_files: Vec<String>,
+    // relative to the app directory, the daemon directory is kept when unset
+    pub working_dir: Option<String>,
+    // user name or uid to run the app as, its primary group is used unless `group` is set
+    pub user: Option<String>,
+    pub group: Option<String>,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    // seconds between SIGTERM and SIGKILL
+    #[serde(default = "default_stop_timeout")]
+    pub stop_timeout: f32,
+    #[serde(default)]
+    pub update: UpdateConfig,
+    pub health: Option<HealthConfig>,
+    #[serde(default)]
+    pub jobs: Vec<JobConfig>,
+    // started by the first request to its routes instead of at boot
+    pub on_demand: Option<OnDemandConfig>,
+    #[serde(default)]
+    pub log: AppLogConfig,
+}
+
+// overrides the memory_lines and memory_bytes of the daemon [logs]
+#[derive(Debug, Deserialize, Default)]
+pub struct AppLogConfig{
+    pub lines: Option<usize>,
+    pub bytes: Option<usize>,
+    // how the levels and fields are read from the output lines
+    #[serde(default)]
+    pub parse: LogParseConfig,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateConfig{
+    // executable relative to the app directory, unicom-app-update (./update.sh) when unset
+    pub hook: Option<String>,
+    // seconds given to the hook
+    #[serde(default = "default_update_timeout")]
+    pub timeout: u64,
+    // seconds for the updated app to register its node before rolling back
+    #[serde(default = "default_update_start_timeout")]
+    pub start_timeout: u64,
+}
+
+impl Default for UpdateConfig{
+    fn default() -> Self {
+        UpdateConfig{
+            hook: None,
+            timeout: default_update_timeout(),
+            start_timeout: default_update_start_timeout(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy{
+    Never,
+    OnFailure,
+    Always,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RestartConfig{
+    #[serde(default = "default_restart_policy")]
+    pub policy: RestartPolicy,
+    // first backoff in seconds, doubled on each restart up to max_delay
+    #[serde(default = "default_restart_delay")]
+    pub delay: f32,
+    #[serde(default = "default_restart_max_delay")]
+    pub max_delay: f32,
+    // more than `limit` restarts in `window` seconds moves the app to Failed
+    #[serde(default = "default_restart_limit")]
+    pub limit: u32,
+    #[serde(default = "default_restart_window")]
+    pub window: u64,
+}
+
+impl Default for RestartConfig{
+    fn default() -> Self {
+        RestartConfig{
+            policy: default_restart_policy(),
+            delay: default_restart_delay(),
+            max_delay: default_restart_max_delay(),
+            limit: default_restart_limit(),
+            window: default_restart_window(),
+        }
+    }
+}
+
+impl RestartConfig{
+    // records a restart and returns the backoff delay, None once the restart limit is reached
+    fn backoff(&self, restarts: &mut Vec<Instant>, now: Instant) -> Option<Duration>{
+        restarts.retain(|time| now.duration_since(*time) < Duration::from_secs(self.window));
+        if restarts.len() >= self.limit as usize{
+            return None
+        }
+        let delay = (self.delay * 2f32.powi(restarts.len() as i32)).min(self.max_delay);
+        restarts.push(now);
+        Some(Duration::from_secs_f32(delay))
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StringOrList{
+    String(String),
+    List(Vec<String>),
+}
+
+fn string_or_list<'de, D>(deserializer: D) -> Result<Vec<String>, D::Error> where D: serde::Deserializer<'de>{
+    Ok(match <StringOrList as serde::Deserialize>::deserialize(deserializer)?{
+        StringOrList::String(value) => vec![value],
+        StringOrList::List(values) => values,
+    })
+}
+
+fn seconds_valid(value: f32) -> bool{
+    value.is_finite() && value >= 0.0 && value <= u32::MAX as f32
+}
+
+fn default_stop_timeout() -> f32{
+    10.0
+}
+
+fn default_update_timeout() -> u64{
+    600
+}
+
+fn default_update_start_timeout() -> u64{
+    60
+}
+
+fn default_after_timeout() -> u64{
+    120
+}
+
+fn default_restart_policy() -> RestartPolicy{
+    RestartPolicy::Never
+}
+
+fn default_restart_delay() -> f32{
+    1.0
+}
+
+fn default_restart_max_delay() -> f32{
+    60.0
+}
+
+fn default_restart_limit() -> u32{
+    5
+}
+
+fn default_restart_window() -> u64{
+    300
 }
 
 impl AppConfig{
     pub async fn read_config(dir: &str) -> Result<AppConfig, UnicomError>{
-        Ok(toml::from_str(&fs::read_to_string(Path::new(dir).join("config.toml")).await?)?)
+        let config: AppConfig = toml::from_str(&fs::read_to_string(Path::new(dir).join("config.toml")).await?)?;
+        // Duration::from_secs_f32 panics on these
+        for (name, value) in [("stop_timeout", config.stop_timeout), ("restart.delay", config.restart.delay), ("restart.max_delay", config.restart.max_delay)]{
+            if !seconds_valid(value){
+                return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("app {} {} must be a number of seconds, got {}", config.name, name, value)))
+            }
+        }
+        Ok(config)
+    }
+
+    pub async fn prepare(&self, cmd: &mut Command, dir: &str) -> Result<(), UnicomError>{
+        for env_file in &self.env_files{
+            let content = fs::read_to_string(Path::new(dir).join(env_file)).await?;
+            for (key, value) in parse_env_file(&content){
+                cmd.env(key, value);
+            }
+        }
+        cmd.envs(&self.env);
+
+        if let Some(working_dir) = &self.working_dir{
+            cmd.current_dir(Path::new(dir).join(working_dir));
+        }
+
+        if let Some(user) = &self.user{
+            let user = match user.parse::<u32>(){
+                Ok(uid) => User::from_uid(Uid::from_raw(uid)),
+                Err(_) => User::from_name(user),
+            }.map_err(std::io::Error::from)?
+            .ok_or(UnicomError::new(UnicomErrorKind::NotFound, &format!("app {} user not found {}", self.name, user)))?;
+
+            cmd.uid(user.uid.as_raw());
+            cmd.gid(user.gid.as_raw());
+            cmd.env("USER", &user.name);
+            cmd.env("HOME", &user.dir);
+        }
+
+        if let Some(group) = &self.group{
+            let group = match group.parse::<u32>(){
+                Ok(gid) => Group::from_gid(Gid::from_raw(gid)),
+                Err(_) => Group::from_name(group),
+            }.map_err(std::io::Error::from)?
+            .ok_or(UnicomError::new(UnicomErrorKind::NotFound, &format!("app {} group not found {}", self.name, group)))?;
+
+            cmd.gid(group.gid.as_raw());
+        }
+
+        Ok(())
     }
 }
 
+fn parse_env_file(content: &str) -> Vec<(String, String)>{
+    let mut ret = Vec::new();
+    for line in content.lines(){
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#'){
+            continue
+        }
+        let line = line.strip_prefix("export ").unwrap_or(line);
+        if let Some((key, value)) = line.split_once('='){
+            let value = value.trim();
+            let value = value.strip_prefix('"').and_then(|value| value.strip_suffix('"'))
+                .or(value.strip_prefix('\'').and_then(|value| value.strip_suffix('\'')))
+                .unwrap_or(value);
+            ret.push((key.trim().to_string(), value.to_string()));
+        }
+    }
+    ret
+}
+
+
 
+#[cfg(test)]
+mod tests{
+    use std::time::{Duration, Instant};
+
+    use super::{RestartConfig, seconds_valid, parse_env_file};
+
+    fn config(limit: u32, window: u64) -> RestartConfig{
+        RestartConfig{
+            limit,
+            window,
+            max_delay: 10.0,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_max_delay(){
+        let config = config(10, 600);
+        let mut restarts = Vec::new();
+        let now = Instant::now();
+        let delays: Vec<Duration> = (0..6).map(|_| config.backoff(&mut restarts, now).unwrap()).collect();
+        assert_eq!(delays, [1.0, 2.0, 4.0, 8.0, 10.0, 10.0].map(Duration::from_secs_f32));
+    }
+
+    #[test]
+    fn backoff_stops_at_the_limit(){
+        let config = config(2, 60);
+        let mut restarts = Vec::new();
+        let now = Instant::now();
+        assert!(config.backoff(&mut restarts, now).is_some());
+        assert!(config.backoff(&mut restarts, now).is_some());
+        assert_eq!(config.backoff(&mut restarts, now), None);
+        assert_eq!(restarts.len(), 2);
+    }
+
+    #[test]
+    fn backoff_forgets_restarts_out_of_the_window(){
+        let config = config(2, 60);
+        let start = Instant::now();
+        let mut restarts = vec![start, start];
+        assert_eq!(config.backoff(&mut restarts, start + Duration::from_secs(30)), None);
+        assert_eq!(config.backoff(&mut restarts, start + Duration::from_secs(60)), Some(Duration::from_secs(1)));
+        assert_eq!(restarts, [start + Duration::from_secs(60)]);
+    }
+
+    #[test]
+    fn env_file_lines(){
+        let content = "# comment\n\nexport HOME_DIR=/srv/app\nNAME = \"media server\"\nQUOTE='a=b'\nBROKEN\nEMPTY=\n";
+        let pairs: Vec<(String, String)> = vec![("HOME_DIR", "/srv/app"), ("NAME", "media server"), ("QUOTE", "a=b"), ("EMPTY", "")]
+            .into_iter().map(|(key, value)| (key.to_string(), value.to_string())).collect();
+        assert_eq!(parse_env_file(content), pairs);
+    }
+
+    #[test]
+    fn seconds_must_fit_a_duration(){
+        assert!(seconds_valid(0.0));
+        assert!(seconds_valid(2.5));
+        assert!(!seconds_valid(-1.0));
+        assert!(!seconds_valid(f32::NAN));
+        assert!(!seconds_valid(f32::INFINITY));
+        assert!(!seconds_valid(1e30));
+    }
+}
@@ -0,0 +1,44 @@
+use std::path::Path;
+
+use unicom_lib::{node::NodeConfig, config::Manifest, error::{UnicomError, UnicomErrorKind}};
+
+use crate::http::check_reserved;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct OnDemandConfig{
+    // routes registered before the app runs, relative to the app directory
+    #[serde(default = "default_manifest")]
+    pub manifest: String,
+    // seconds without request before the app is stoped
+    #[serde(default = "default_idle_timeout")]
+    pub idle_timeout: u64,
+    // seconds a request waits for the node to connect
+    #[serde(default = "default_start_timeout")]
+    pub start_timeout: u64,
+}
+
+// the manifest paths are relative to the app directory, as for the framwork manifest
+pub fn read_manifest(name: &str, dir: &str, config: &OnDemandConfig) -> Result<NodeConfig, UnicomError>{
+    let content = std::fs::read_to_string(Path::new(dir).join(&config.manifest))?;
+    let manifest: Manifest = toml::from_str(&content)?;
+
+    let node_config: NodeConfig = manifest.try_into()
+        .map_err(|_| UnicomError::new(UnicomErrorKind::InputInvalid, &format!("app {} invalid manifest {}", name, config.manifest)))?;
+    if node_config.name != name{
+        return Err(UnicomError::new(UnicomErrorKind::InputInvalid, &format!("app {} manifest is for node {}", name, node_config.name)))
+    }
+    check_reserved(&node_config)?;
+    Ok(node_config)
+}
+
+fn default_manifest() -> String{
+    "manifest.toml".to_string()
+}
+
+fn default_idle_timeout() -> u64{
+    600
+}
+
+fn default_start_timeout() -> u64{
+    30
+}
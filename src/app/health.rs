@@ -0,0 +1,99 @@
+use std::time::{Duration, Instant};
+
+use chrono::Local;
+use hyper::{Client, Request, Body};
+use serde_json::Map;
+use tokio::time::timeout;
+use unicom_lib::node::api::MethodKind;
+
+use crate::{SERVER, server::HEALTH_PROBE_HEADER};
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct HealthConfig{
+    // node api of the app called with GET, any response is healthy
+    pub api: Option<String>,
+    // "/path" on the daemon http server or a full url, a 2xx status is healthy
+    pub http: Option<String>,
+    // seconds between two checks
+    #[serde(default = "default_interval")]
+    pub interval: u64,
+    // seconds before a check fails
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    // consecutive failures moving a running app to Unhealthy
+    #[serde(default = "default_unhealthy_threshold")]
+    pub unhealthy_threshold: u32,
+    // consecutive successes moving an unhealthy app back to Running
+    #[serde(default = "default_healthy_threshold")]
+    pub healthy_threshold: u32,
+    // checks kept in the app status
+    #[serde(default = "default_history")]
+    pub history: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthCheck{
+    pub time: String,
+    pub healthy: bool,
+    // milliseconds
+    pub duration: u64,
+    pub error: Option<String>,
+}
+
+impl HealthConfig{
+    pub async fn check(&self, name: &str) -> HealthCheck{
+        let begin = Instant::now();
+        let error = match timeout(Duration::from_secs(self.timeout), self.probe(name)).await{
+            Ok(Ok(())) => None,
+            Ok(Err(e)) => Some(e),
+            Err(_) => Some(format!("no answer after {}s", self.timeout)),
+        };
+        HealthCheck{
+            time: Local::now().to_rfc3339(),
+            healthy: error.is_none(),
+            duration: begin.elapsed().as_millis() as u64,
+            error,
+        }
+    }
+
+    async fn probe(&self, name: &str) -> Result<(), String>{
+        if let Some(api) = &self.api{
+            let node = SERVER.controller.connected_node(name).await.ok_or(format!("node {} not connected", name))?;
+            let api = node.api(api).map_err(|e| format!("{:?}", e))?;
+            node.request(api, MethodKind::GET, Map::new()).await.map_err(|e| format!("{:?}", e))?;
+        }
+        if let Some(http) = &self.http{
+            let url = match http.starts_with('/'){
+                true => format!("http://{}{}", SERVER.server_addr, http),
+                false => http.clone(),
+            };
+            let request = Request::get(&url).header(HEALTH_PROBE_HEADER, "1").body(Body::empty())
+                .map_err(|e| format!("invalid health url {}: {}", url, e))?;
+            let response = Client::new().request(request).await.map_err(|e| e.to_string())?;
+            if !response.status().is_success(){
+                return Err(format!("{} answered {}", url, response.status()))
+            }
+        }
+        Ok(())
+    }
+}
+
+fn default_interval() -> u64{
+    30
+}
+
+fn default_timeout() -> u64{
+    5
+}
+
+fn default_unhealthy_threshold() -> u32{
+    3
+}
+
+fn default_healthy_threshold() -> u32{
+    1
+}
+
+fn default_history() -> usize{
+    20
+}
@@ -0,0 +1,92 @@
+use std::{path::{Path, PathBuf}, fs::File, io};
+
+use flate2::read::GzDecoder;
+use tokio::{fs, process::Command, task};
+use unicom_lib::error::{UnicomError, UnicomErrorKind};
+
+// unpacks a tar.gz, a zip or clones a git repository into `staging`, returns the app root inside it
+pub async fn fetch(source: &str, staging: &Path) -> Result<PathBuf, UnicomError>{
+    let source_path = Path::new(source);
+    if !source_path.exists(){
+        return Err(UnicomError::new(UnicomErrorKind::NotFound, &format!("app source not found {}", source)))
+    }
+
+    if source_path.is_dir(){
+        if !source_path.join(".git").exists() && !source.ends_with(".git"){
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("app source directory is not a git repository {}", source)))
+        }
+        let status = Command::new("git").arg("clone").arg("--depth").arg("1")
+            .arg(format!("file://{}", source_path.canonicalize()?.display()))
+            .arg(staging)
+            .status().await?;
+        if !status.success(){
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("git clone {} failed with {}", source, status)))
+        }
+        fs::remove_dir_all(staging.join(".git")).await?;
+        return Ok(staging.to_path_buf())
+    }
+
+    fs::create_dir_all(staging).await?;
+    let archive = source_path.to_path_buf();
+    let destination = staging.to_path_buf();
+    // both unpackers refuse entries escaping `destination`: tar checks each entry with unpack_in,
+    // zip only extracts entries with an enclosed name
+    if source.ends_with(".tar.gz") || source.ends_with(".tgz"){
+        task::spawn_blocking(move || -> io::Result<()> {
+            tar::Archive::new(GzDecoder::new(File::open(archive)?)).unpack(destination)
+        }).await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+    }
+    else if source.ends_with(".zip"){
+        task::spawn_blocking(move || -> io::Result<()> {
+            zip::ZipArchive::new(File::open(archive)?)
+                .and_then(|mut zip| zip.extract(destination))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+        }).await.map_err(|e| io::Error::new(io::ErrorKind::Other, e))??;
+    }
+    else{
+        return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("unknown app archive format {}", source)))
+    }
+
+    app_root(staging).await
+}
+
+// the app name becomes a directory of the app dir, only a single plain component is accepted
+pub fn check_name(name: &str) -> Result<(), UnicomError>{
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-'){
+        return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("invalid app name {:?}, only letters, digits, '_' and '-' are allowed", name)))
+    }
+    Ok(())
+}
+
+// archives often wrap the app in a single top directory
+async fn app_root(staging: &Path) -> Result<PathBuf, UnicomError>{
+    if staging.join("config.toml").exists(){
+        return Ok(staging.to_path_buf())
+    }
+    let mut entries = fs::read_dir(staging).await?;
+    let mut dirs = Vec::new();
+    while let Some(entry) = entries.next_entry().await?{
+        if entry.file_type().await?.is_dir(){
+            dirs.push(entry.path());
+        }
+    }
+    if dirs.len() == 1 && dirs[0].join("config.toml").exists(){
+        return Ok(dirs.remove(0))
+    }
+    Err(UnicomError::new(UnicomErrorKind::InputInvalid, "app config.toml not found in archive"))
+}
+
+#[cfg(test)]
+mod tests{
+    use super::check_name;
+
+    #[test]
+    fn names_are_plain_directory_names(){
+        assert!(check_name("media-server_2").is_ok());
+        assert!(check_name("").is_err());
+        assert!(check_name("..").is_err());
+        assert!(check_name("a/b").is_err());
+        assert!(check_name("app name").is_err());
+        assert!(check_name("médias").is_err());
+    }
+}
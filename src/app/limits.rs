@@ -0,0 +1,250 @@
+use std::{path::{Path, PathBuf}, io::{self, Read, Write}, fs::File, os::unix::io::{AsRawFd, FromRawFd}, thread::{self, JoinHandle}};
+
+use tokio::{fs, process::Command};
+use unicom_lib::error::{UnicomError, UnicomErrorKind};
+
+use super::process::ExitInfo;
+
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+#[derive(Debug, Deserialize, Default, Clone)]
+pub struct LimitsConfig{
+    // bytes of address space (RLIMIT_AS)
+    pub memory: Option<u64>,
+    // seconds of cpu time (RLIMIT_CPU), the app gets SIGXCPU when exceeded
+    pub cpu_time: Option<u64>,
+    pub open_files: Option<u64>,
+    // counted for the whole user the app runs as (RLIMIT_NPROC)
+    pub processes: Option<u64>,
+    // a negative nice needs the app to keep running as root
+    pub nice: Option<i32>,
+    pub ionice_class: Option<IoniceClass>,
+    // 0 (highest) to 7 (lowest) for realtime and best-effort
+    pub ionice_level: Option<u8>,
+    pub cgroup: Option<CgroupConfig>,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+pub enum IoniceClass{
+    Realtime,
+    BestEffort,
+    Idle,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct CgroupConfig{
+    // parent directory of the app cgroups
+    pub parent: Option<String>,
+    // written as is, "512M", "max"
+    pub memory_max: Option<String>,
+    // "<quota> <period>" in microseconds, "50000 100000" is half a cpu
+    pub cpu_max: Option<String>,
+}
+
+impl LimitsConfig{
+    // runs in the forked child, only async-signal-safe calls are allowed
+    pub fn apply(&self, cmd: &mut Command){
+        let limits = self.clone();
+        unsafe{
+            cmd.pre_exec(move || {
+                if let Some(memory) = limits.memory{
+                    set_rlimit(libc::RLIMIT_AS as _, memory)?;
+                }
+                if let Some(cpu_time) = limits.cpu_time{
+                    set_rlimit(libc::RLIMIT_CPU as _, cpu_time)?;
+                }
+                if let Some(open_files) = limits.open_files{
+                    set_rlimit(libc::RLIMIT_NOFILE as _, open_files)?;
+                }
+                if let Some(processes) = limits.processes{
+                    set_rlimit(libc::RLIMIT_NPROC as _, processes)?;
+                }
+                if let Some(nice) = limits.nice{
+                    if libc::setpriority(libc::PRIO_PROCESS, 0, nice) != 0{
+                        return Err(io::Error::last_os_error())
+                    }
+                }
+                if let Some(class) = limits.ionice_class{
+                    let class: libc::c_long = match class{
+                        IoniceClass::Realtime => 1,
+                        IoniceClass::BestEffort => 2,
+                        IoniceClass::Idle => 3,
+                    };
+                    let level = limits.ionice_level.unwrap_or(4).min(7) as libc::c_long;
+                    // IOPRIO_WHO_PROCESS, current process
+                    if libc::syscall(libc::SYS_ioprio_set, 1 as libc::c_long, 0 as libc::c_long, (class << 13) | level) != 0{
+                        return Err(io::Error::last_os_error())
+                    }
+                }
+                Ok(())
+            });
+        }
+    }
+}
+
+// the resource type differs between libcs (u32 on glibc, c_int on musl)
+fn set_rlimit(resource: libc::c_int, value: u64) -> io::Result<()>{
+    let limit = libc::rlimit{
+        rlim_cur: value as libc::rlim_t,
+        rlim_max: value as libc::rlim_t,
+    };
+    if unsafe{ libc::setrlimit(resource as _, &limit) } != 0{
+        return Err(io::Error::last_os_error())
+    }
+    Ok(())
+}
+
+pub struct Cgroup{
+    path: PathBuf,
+    oom_kills: u64,
+}
+
+impl Cgroup{
+    pub async fn create(name: &str, config: &CgroupConfig) -> Result<Cgroup, UnicomError>{
+        if !Path::new(CGROUP_ROOT).join("cgroup.controllers").exists(){
+            return Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("app {} has a cgroup configured but the host has no cgroup v2 hierarchy", name)))
+        }
+        let parent = PathBuf::from(config.parent.clone().unwrap_or(format!("{}/unicom", CGROUP_ROOT)));
+        fs::create_dir_all(&parent).await?;
+
+        // controllers must be enabled on every level down to the app cgroup,
+        // a level already delegated (systemd) refuses the write and is left as is
+        let mut level = PathBuf::from(CGROUP_ROOT);
+        for component in parent.strip_prefix(CGROUP_ROOT).unwrap_or(Path::new("")).components(){
+            fs::write(level.join("cgroup.subtree_control"), "+memory +cpu").await.unwrap_or_default();
+            level = level.join(component);
+        }
+        fs::write(level.join("cgroup.subtree_control"), "+memory +cpu").await.unwrap_or_default();
+
+        let path = parent.join(name);
+        fs::create_dir_all(&path).await?;
+        if let Some(memory_max) = &config.memory_max{
+            fs::write(path.join("memory.max"), memory_max).await?;
+        }
+        if let Some(cpu_max) = &config.cpu_max{
+            fs::write(path.join("cpu.max"), cpu_max).await?;
+        }
+
+        let mut cgroup = Cgroup{
+            path,
+            oom_kills: 0,
+        };
+        cgroup.oom_kills = cgroup.read_oom_kills().await;
+        Ok(cgroup)
+    }
+
+    // the child sends its pid and waits before exec until a thread of the daemon moved it into the group,
+    // the app never runs outside of it. The write is done by the daemon as the child may already run as
+    // the app user. Must be the last pre_exec of the command, wait is called once spawn returned
+    pub fn attach(&self, cmd: &mut Command) -> Result<CgroupAttach, UnicomError>{
+        let (pid_read, pid_write) = pipe()?;
+        let (go_read, go_write) = pipe()?;
+        let procs = self.path.join("cgroup.procs");
+        let (pid_read_fd, go_write_fd) = (pid_read.as_raw_fd(), go_write.as_raw_fd());
+
+        let helper = thread::spawn(move || -> Result<(), UnicomError>{
+            let (mut pid_read, mut go_write) = (pid_read, go_write);
+            let mut pid = [0u8; 4];
+            // no pid, the child failed before reaching the attach and spawn gives the error
+            if pid_read.read_exact(&mut pid).is_err(){
+                return Ok(())
+            }
+            std::fs::write(&procs, i32::from_ne_bytes(pid).to_string())?;
+            go_write.write_all(&[1])?;
+            Ok(())
+        });
+
+        let (pid_fd, go_fd) = (pid_write.as_raw_fd(), go_read.as_raw_fd());
+        unsafe{
+            cmd.pre_exec(move || {
+                // the copies of the daemon ends would keep the pipe open, no EOF when the daemon gives up
+                libc::close(pid_read_fd);
+                libc::close(go_write_fd);
+                let pid = libc::getpid().to_ne_bytes();
+                if libc::write(pid_fd, pid.as_ptr() as *const libc::c_void, pid.len()) != pid.len() as isize{
+                    return Err(io::Error::last_os_error())
+                }
+                let mut go = 0u8;
+                loop{
+                    match libc::read(go_fd, &mut go as *mut u8 as *mut libc::c_void, 1){
+                        1 if go == 1 => return Ok(()),
+                        -1 if io::Error::last_os_error().raw_os_error() == Some(libc::EINTR) => continue,
+                        // the daemon could not move the child, no allocation after fork
+                        _ => return Err(io::Error::from_raw_os_error(libc::EPERM)),
+                    }
+                }
+            });
+        }
+
+        Ok(CgroupAttach{
+            pid_write,
+            go_read,
+            helper,
+        })
+    }
+
+    // true when the kernel oom killed a process of the group since its creation
+    pub async fn oom_killed(&self) -> bool{
+        self.read_oom_kills().await > self.oom_kills
+    }
+
+    pub async fn remove(&self){
+        // fails while orphans of the app are still alive, the group is then reused next start
+        fs::remove_dir(&self.path).await.unwrap_or_default();
+    }
+
+    async fn read_oom_kills(&self) -> u64{
+        let events = fs::read_to_string(self.path.join("memory.events")).await.unwrap_or_default();
+        for line in events.lines(){
+            if let Some(count) = line.strip_prefix("oom_kill "){
+                return count.trim().parse().unwrap_or(0)
+            }
+        }
+        0
+    }
+}
+
+pub struct CgroupAttach{
+    pid_write: File,
+    go_read: File,
+    helper: JoinHandle<Result<(), UnicomError>>,
+}
+
+impl CgroupAttach{
+    // closes the ends given to the child, the thread ends even when the child never ran
+    pub fn wait(self) -> Result<(), UnicomError>{
+        drop(self.pid_write);
+        drop(self.go_read);
+        match self.helper.join(){
+            Ok(result) => result,
+            Err(_) => Err(UnicomError::new(UnicomErrorKind::NotAllowed, "cgroup attach thread panicked")),
+        }
+    }
+}
+
+// close on exec, the app does not inherit them
+fn pipe() -> Result<(File, File), UnicomError>{
+    let mut fds = [0 as libc::c_int; 2];
+    if unsafe{ libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0{
+        return Err(io::Error::last_os_error().into())
+    }
+    Ok(unsafe{ (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) })
+}
+
+// explains an exit caused by one of the limits
+pub async fn limit_reason(info: &ExitInfo, limits: &LimitsConfig, cgroup: Option<&Cgroup>) -> Option<String>{
+    match info.signal{
+        Some(libc::SIGXCPU) => return Some(format!("cpu time limit exceeded ({}s)", limits.cpu_time.unwrap_or_default())),
+        Some(libc::SIGXFSZ) => return Some("file size limit exceeded".to_string()),
+        Some(libc::SIGKILL) => {
+            if let Some(cgroup) = cgroup{
+                if cgroup.oom_killed().await{
+                    return Some("cgroup memory limit exceeded (oom kill)".to_string())
+                }
+            }
+        },
+        _ => (),
+    }
+    None
+}
@@ -1,65 +1,145 @@
-use std::{sync::Arc, fs, path::Path};
+use std::{sync::Arc, fs, path::{Path, PathBuf}, collections::{HashSet, HashMap}, time::Duration};
 
-use tokio::sync::Mutex;
-use unicom_lib::{error::UnicomError, node::Node};
+use tokio::{sync::Mutex, fs::{self as async_fs, OpenOptions}, io::AsyncWriteExt, process::Command};
+use chrono::Local;
+use unicom_lib::{error::{UnicomError, UnicomErrorKind}, node::{Node, NodeConfig}};
+use uuid::Uuid;
 
-use self::app::{App, AppConfig, AppState};
+use crate::{LOGGER, SERVER, log::parse::LogParser};
+
+use self::app::{App, AppConfig, AppStatus, AppState};
 
 mod app;
+mod process;
+mod limits;
+mod watch;
+mod install;
+mod health;
+mod demand;
 
 pub struct AppControler{
     apps: Mutex<Vec<Arc<App>>>,
+    // names of the connected nodes, an app dependency is satisfied once its node is here
+    nodes: Arc<Mutex<HashSet<String>>>,
+    disabled: Mutex<HashSet<String>>,
     location: String,
     stream: String,
+    state_path: String,
+    archive_dir: String,
     
 
 }
 
 impl AppControler{
-    pub fn new(location: &str, stream: &str) -> AppControler{
+    pub fn new(location: &str, stream: &str, state_path: &str, archive_dir: &str) -> AppControler{
         AppControler{
             apps: Mutex::new(Vec::new()),
+            nodes: Arc::new(Mutex::new(HashSet::new())),
+            disabled: Mutex::new(HashSet::new()),
             location: location.to_string(),
             stream: stream.to_string(),
+            state_path: state_path.to_string(),
+            archive_dir: archive_dir.to_string(),
         }
     }
 
     pub async fn init(&self) -> Result<(),UnicomError>{
+        if let Err(e) = self.load_state().await{
+            LOGGER.error("apps state load", e).await;
+        }
+
         for path in fs::read_dir(Path::new(&self.location))?{
-            println!("path {:?} {}", &path, self.location);
+            LOGGER.trace("apps", format!("path {:?} {}", &path, self.location)).await;
             let path = path?.path();
+            // .install- and .update- directories are leftovers of an interrupted install or update
+            if path.file_name().map(|name| name.to_string_lossy().starts_with('.')).unwrap_or(false){
+                continue
+            }
             if path.is_dir() {
                 self.load(path.to_str().unwrap(), false).await?;
             }
         }
 
-        self.start_app(None).await;
+        let mut apps = self.apps.lock().await;
+        let (mut sorted, cyclic, blocked) = sort_apps(&apps);
+        for app in &cyclic{
+            LOGGER.error("apps init", UnicomError::new(UnicomErrorKind::ParameterInvalid, 
+                            &format!("app {} is part of a dependency cycle {:?}", app.config.name, app.config.after))).await;
+            app.set_failed().await;
+        }
+        for app in &blocked{
+            LOGGER.error("apps init", UnicomError::new(UnicomErrorKind::ParameterInvalid, 
+                            &format!("app {} depends on a dependency cycle {:?}", app.config.name, app.config.after))).await;
+            app.set_failed().await;
+        }
+        sorted.extend(cyclic);
+        sorted.extend(blocked);
+        *apps = sorted;
+
+        for app in apps.iter(){
+            if app.config.on_demand.is_none() && app.get_state().await == AppState::Waiting{
+                spawn_start(app.clone());
+            }
+        }
         
         Ok(())
     }
 
     pub async fn add_node(&self, node: &Arc<Node>){
+        self.nodes.lock().await.insert(node.name.clone());
         for app in &*self.apps.lock().await{
             if app.config.name == node.name{
                 app.set_running().await;
                 break
             }
         }
-        self.start_app(Some(&node.name)).await;
     }
 
     pub async fn remove_node(&self, node: &Arc<Node>){
+        self.nodes.lock().await.remove(&node.name);
         for app in &*self.apps.lock().await{
             if app.config.name == node.name{
                 app.set_zombie().await;
+                // the next request starts it again
+                if let Some(manifest) = &app.manifest{
+                    if let Err(e) = SERVER.controller.router.add(manifest).await{
+                        LOGGER.error(&format!("app {} routes", app.config.name), e).await;
+                    }
+                }
+            }
+        }
+    }
+
+    // true once the on demand app `name` is running, false when no such app exists
+    pub async fn demand(&self, name: &str) -> Result<bool, UnicomError>{
+        let app = match self.app(name).await{
+            Ok(app) => app,
+            Err(_) => return Ok(false),
+        };
+        if app.config.on_demand.is_none(){
+            return Ok(false)
+        }
+        app.demand().await?;
+        Ok(true)
+    }
+
+    // keeps an on demand app from being stoped as idle
+    pub async fn touch(&self, name: &str){
+        for app in &*self.apps.lock().await{
+            if app.config.name == name && app.config.on_demand.is_some(){
+                app.touch().await;
+                break
             }
         }
     }
 
-    pub async fn status(&self) -> Result<Vec<(String, AppState)>, UnicomError>{
+    pub async fn status(&self) -> Result<Vec<AppStatus>, UnicomError>{
+        let nodes = self.nodes.lock().await.clone();
         let mut ret = Vec::new();
         for app in &*self.apps.lock().await{
-            ret.push((app.config.name.clone(), app.get_state().await))
+            let mut status = app.status().await;
+            status.waiting_for = app.config.after.iter().filter(|name| !nodes.contains(*name)).cloned().collect();
+            ret.push(status)
         }
         Ok(ret)
     }
@@ -70,9 +150,78 @@ impl AppControler{
         Ok(())
     }
 
+    // stops the app, snapshots it, runs its update hook and starts it again, the snapshot is restored and started if any step fails
     pub async fn update(&self, name: &str) -> Result<(), UnicomError>{
         let app = self.app(name).await?;
-        app.update().await?;
+        let snapshot = Path::new(&self.location).join(format!(".update-{}", name));
+        if snapshot.exists(){
+            async_fs::remove_dir_all(&snapshot).await?;
+        }
+        // the hook and the file swap must not run under the running app, load starts it again
+        app.stop().await?;
+        if let Err(e) = copy_dir(Path::new(&app.dir), &snapshot).await{
+            self.load(&app.dir, true).await?;
+            return Err(e)
+        }
+
+        // the hook rewrites the directory, auto reload must not restart the app meanwhile
+        let auto_reload = std::mem::replace(&mut *app.auto_reload.lock().await, false);
+        let result = self.apply_update(&app).await;
+        *app.auto_reload.lock().await = auto_reload;
+
+        match result{
+            Ok(()) => {
+                async_fs::remove_dir_all(&snapshot).await?;
+                LOGGER.app_stdout(name, "updated".to_string()).await;
+                Ok(())
+            },
+            Err(e) => {
+                LOGGER.app_stderr(name, format!("update failed, rolling back: {:?}", e)).await;
+                if let Err(rollback_error) = self.rollback(&app, &snapshot).await{
+                    LOGGER.error(&format!("app {} rollback", name), rollback_error).await;
+                }
+                Err(e)
+            },
+        }
+    }
+
+    async fn apply_update(&self, app: &Arc<App>) -> Result<(), UnicomError>{
+        app.run_update_hook().await?;
+        let config = AppConfig::read_config(&app.dir).await?;
+        if config.name != app.config.name{
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, 
+                        &format!("app {} renamed to {} by its update", app.config.name, config.name)))
+        }
+        self.load(&app.dir, true).await?;
+
+        let updated = self.app(&app.config.name).await?;
+        if updated.get_state().await == AppState::Disabled || updated.config.on_demand.is_some(){
+            return Ok(())
+        }
+        let start_timeout = updated.config.update.start_timeout;
+        if !updated.wait_running(Duration::from_secs(start_timeout)).await{
+            return Err(UnicomError::new(UnicomErrorKind::NotFound, 
+                        &format!("app {} not running {}s after its update", app.config.name, start_timeout)))
+        }
+        Ok(())
+    }
+
+    async fn rollback(&self, app: &Arc<App>, snapshot: &Path) -> Result<(), UnicomError>{
+        if let Ok(current) = self.app(&app.config.name).await{
+            current.stop().await?;
+        }
+        async_fs::remove_dir_all(&app.dir).await?;
+        async_fs::rename(snapshot, &app.dir).await?;
+        self.load(&app.dir, true).await?;
+        LOGGER.app_stdout(&app.config.name, "rolled back".to_string()).await;
+        Ok(())
+    }
+
+    // runs `cmd` as the app would run, in its directory with its user and environment
+    pub async fn prepare_command(&self, name: &str, cmd: &mut Command) -> Result<(), UnicomError>{
+        let app = self.app(name).await?;
+        app.config.prepare(cmd, &app.dir).await?;
+        cmd.current_dir(&app.dir);
         Ok(())
     }
 
@@ -81,8 +230,129 @@ impl AppControler{
         Ok(())
     }
 
+    pub async fn start(&self, name: &str) -> Result<(), UnicomError>{
+        self.app(name).await?.start().await
+    }
+
+    pub async fn restart(&self, name: &str) -> Result<(), UnicomError>{
+        let app = self.app(name).await?;
+        app.stop().await?;
+        app.start().await
+    }
+
+    // an enabled app is not started, app_start does it
+    pub async fn enable(&self, name: &str) -> Result<(), UnicomError>{
+        let app = self.app(name).await?;
+        if self.disabled.lock().await.remove(name){
+            self.save_state().await?;
+        }
+        app.set_enabled().await;
+        Ok(())
+    }
+
+    pub async fn disable(&self, name: &str) -> Result<(), UnicomError>{
+        let app = self.app(name).await?;
+        if self.disabled.lock().await.insert(name.to_string()){
+            self.save_state().await?;
+        }
+        // disabled first, a supervisor or health check running meanwhile must not start it again
+        app.set_disabled().await;
+        app.stop().await?;
+        Ok(())
+    }
+
+    // installs an app from a tar.gz, a zip or a local git repository and starts it, returns its name
+    pub async fn install(&self, source: &str) -> Result<String, UnicomError>{
+        let staging = Path::new(&self.location).join(format!(".install-{}", Uuid::new_v4()));
+        let result = self.install_from(source, &staging).await;
+        if staging.exists(){
+            async_fs::remove_dir_all(&staging).await.unwrap_or_default();
+        }
+        result
+    }
+
+    async fn install_from(&self, source: &str, staging: &Path) -> Result<String, UnicomError>{
+        let root = install::fetch(source, staging).await?;
+        let config = AppConfig::read_config(root.to_str().unwrap()).await?;
+        install::check_name(&config.name)?;
+        // the manifest is checked before the directory takes the app name
+        if let Some(on_demand) = &config.on_demand{
+            demand::read_manifest(&config.name, root.to_str().unwrap(), on_demand)?;
+        }
+        if self.app(&config.name).await.is_ok(){
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("app name already exist {}", &config.name)))
+        }
+        let dir = Path::new(&self.location).join(&config.name);
+        if dir.exists(){
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("app directory already exist {}", dir.display())))
+        }
+        async_fs::rename(&root, &dir).await?;
+
+        if let Err(e) = self.load(dir.to_str().unwrap(), false).await{
+            async_fs::remove_dir_all(&dir).await.unwrap_or_default();
+            return Err(e)
+        }
+        let app = self.app(&config.name).await?;
+        if app.config.on_demand.is_none() && app.get_state().await == AppState::Waiting{
+            spawn_start(app);
+        }
+        Ok(config.name)
+    }
+
+    // stops and unregisters the app, its directory is moved to the archive unless `delete`
+    pub async fn uninstall(&self, name: &str, delete: bool) -> Result<(), UnicomError>{
+        let app = match self.remove_app(name).await{
+            Some(app) => app,
+            None => return Err(UnicomError::new(UnicomErrorKind::NotFound, &format!("app not found {}", name))),
+        };
+        app.stop().await?;
+        SERVER.controller.scheduler.set_app_jobs(name, &Vec::new()).await;
+        SERVER.controller.router.remove_routes(name).await;
+
+        if self.disabled.lock().await.remove(name){
+            self.save_state().await?;
+        }
+
+        if delete{
+            async_fs::remove_dir_all(&app.dir).await?;
+        }
+        else{
+            async_fs::create_dir_all(&self.archive_dir).await?;
+            let archive = PathBuf::from(&self.archive_dir).join(format!("{}-{}", name, Local::now().format("%Y%m%d%H%M%S")));
+            async_fs::rename(&app.dir, &archive).await?;
+        }
+        Ok(())
+    }
+
+    async fn load_state(&self) -> Result<(), UnicomError>{
+        if !Path::new(&self.state_path).exists(){
+            return Ok(())
+        }
+        let state: AppsState = serde_json::from_str(&async_fs::read_to_string(&self.state_path).await?)?;
+        *self.disabled.lock().await = state.disabled.into_iter().collect();
+        Ok(())
+    }
+
+    async fn save_state(&self) -> Result<(), UnicomError>{
+        let mut disabled: Vec<String> = self.disabled.lock().await.iter().cloned().collect();
+        disabled.sort();
+        let data = serde_json::to_string(&AppsState{ disabled })?;
+        let tmp_path = format!("{}.tmp", &self.state_path);
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path).await?;
+        file.write_all(data.as_bytes()).await?;
+        file.sync_data().await?;
+        drop(file);
+        async_fs::rename(&tmp_path, &self.state_path).await?;
+        Ok(())
+    }
+
     pub async fn close(&self){
         let apps = &mut *self.apps.lock().await;
+        // dependents stop before their dependencies
+        let (mut sorted, cyclic, blocked) = sort_apps(apps);
+        sorted.extend(cyclic);
+        sorted.extend(blocked);
+        *apps = sorted;
         loop{
             let app = match apps.pop(){
                 Some(app) => app,
@@ -90,7 +360,7 @@ impl AppControler{
             };
 
             if let Err(e) = app.stop().await{
-                println!("stop app error {}:{:?}", app.config.name, e);
+                LOGGER.error(&format!("stop app {}", app.config.name), e).await;
             };
         }
     }
@@ -98,66 +368,205 @@ impl AppControler{
 
     async fn load(&self, dir: &str, reload: bool) -> Result<(), UnicomError>{
         let config = AppConfig::read_config(dir).await?;
+        let manifest = match &config.on_demand{
+            Some(on_demand) => Some(demand::read_manifest(&config.name, dir, on_demand)?),
+            None => None,
+        };
 
-        if let Some(index) = self.get_app(&config.name).await{
-            if !reload{
-                return Err(UnicomError::new(unicom_lib::error::UnicomErrorKind::ParameterInvalid, &format!("app name already exist {}", &config.name)))
-            }
-            let app = self.apps.lock().await.remove(index);
+        if !reload && self.app(&config.name).await.is_ok(){
+            return Err(UnicomError::new(unicom_lib::error::UnicomErrorKind::ParameterInvalid, &format!("app name already exist {}", &config.name)))
+        }
+        if let Some(app) = self.remove_app(&config.name).await{
             app.stop().await?;
-
         }
 
-        let app = self.create_app(dir, config).await;
+        let app = self.create_app(dir, config, manifest).await;
 
-        if reload{
+        if self.disabled.lock().await.contains(&app.config.name){
+            app.set_disabled().await;
+        }
+        else if reload && app.config.on_demand.is_none(){
             app.start().await?;
         }
 
         Ok(())
     }
 
-    async fn get_app(&self, name: &str) -> Option<usize>{
-        let apps = self.apps.lock().await;
-        for (index, app) in apps.iter().enumerate(){
-            if app.config.name == name{
-                return Some(index)
-            }
-        }
-        None
+    // lookup and removal under the same guard, a concurrent load or install moves the indexes
+    async fn remove_app(&self, name: &str) -> Option<Arc<App>>{
+        let mut apps = self.apps.lock().await;
+        let index = apps.iter().position(|app| app.config.name == name)?;
+        Some(apps.remove(index))
     }
 
     async fn app(&self, name: &str) -> Result<Arc<App>, UnicomError>{
-        match self.get_app(name).await{
-            Some(index) => {
-                Ok(self.apps.lock().await[index].clone())
-            },
+        match self.apps.lock().await.iter().find(|app| app.config.name == name){
+            Some(app) => Ok(app.clone()),
             None => return Err(UnicomError::new(unicom_lib::error::UnicomErrorKind::NotFound, 
                             &format!("app not found {}", name))),
         }
     }
 
-    async fn create_app(&self, dir: &str, config: AppConfig) -> Arc<App>{
-        println!("app directory: {}", dir);
-        let app = Arc::new(App::new(dir, config, &self.stream));
+    async fn create_app(&self, dir: &str, config: AppConfig, manifest: Option<NodeConfig>) -> Arc<App>{
+        LOGGER.debug("apps", format!("app directory: {}", dir)).await;
+        let app = Arc::new(App::new(dir, config, manifest, &self.stream, self.nodes.clone()));
+        let parser = match LogParser::new(&app.config.log.parse){
+            Ok(parser) => Some(parser),
+            Err(e) => {
+                LOGGER.error(&format!("app {} log rules", app.config.name), e).await;
+                None
+            },
+        };
+        LOGGER.logs.lock().await.configure_app(&app.config.name, app.config.log.lines, app.config.log.bytes, parser);
+        if let Err(e) = app.watch().await{
+            LOGGER.error(&format!("auto reload watch {}", app.config.name), e).await;
+        }
+        SERVER.controller.scheduler.set_app_jobs(&app.config.name, &app.config.jobs).await;
+        if let Some(manifest) = &app.manifest{
+            SERVER.controller.router.remove_routes(&app.config.name).await;
+            if let Err(e) = SERVER.controller.router.add(manifest).await{
+                LOGGER.error(&format!("app {} routes", app.config.name), e).await;
+            }
+        }
         let ret = app.clone();
         let mut apps = self.apps.lock().await;
         apps.push(app);
 
         return ret
     }
+}
 
-    async fn start_app(&self, after: Option<&String>){
-        for app in &*self.apps.lock().await{
-            if app.config.after.is_none(){
-                if let Err(e) = app.start().await{
-                    println!("Error will starting {} : {:?}", app.config.name, e);
-                }
-            } else if after.is_some() && app.config.after.as_ref().unwrap() == after.unwrap(){
-                if let Err(e) = app.start().await{
-                    println!("Error will starting {} : {:?}", app.config.name, e);
+#[derive(Debug, Deserialize, Serialize, Default)]
+struct AppsState{
+    #[serde(default)]
+    disabled: Vec<String>,
+}
+
+// the start waits there for the dependencies of the app
+fn spawn_start(app: Arc<App>){
+    tokio::spawn(async move{
+        if let Err(e) = app.start().await{
+            LOGGER.error(&format!("start app {}", app.config.name), e).await;
+        }
+    });
+}
+
+// keeps permissions, owners and symlinks of the app files
+async fn copy_dir(source: &Path, destination: &Path) -> Result<(), UnicomError>{
+    let status = Command::new("cp").arg("-a").arg(source).arg(destination).status().await?;
+    if !status.success(){
+        return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, 
+                    &format!("copy {} to {} failed with {}", source.display(), destination.display(), status)))
+    }
+    Ok(())
+}
+
+// topological order of the apps, dependencies first, the second list holds the apps caught in a cycle
+// dependencies first, then the apps of a cycle and the apps depending on a cycle without being part of one
+fn sort_apps(apps: &Vec<Arc<App>>) -> (Vec<Arc<App>>, Vec<Arc<App>>, Vec<Arc<App>>){
+    let names: HashSet<&String> = apps.iter().map(|app| &app.config.name).collect();
+    let mut pending: HashMap<&String, usize> = HashMap::new();
+    for app in apps{
+        // a dependency on a node which is not an app does not constrain the order, a repeated one counts once
+        let count = app.config.after.iter().filter(|name| names.contains(name)).collect::<HashSet<_>>().len();
+        pending.insert(&app.config.name, count);
+    }
+
+    let mut sorted: Vec<Arc<App>> = Vec::new();
+    let mut done: HashSet<&String> = HashSet::new();
+    loop{
+        let ready: Vec<&Arc<App>> = apps.iter()
+            .filter(|app| !done.contains(&app.config.name) && pending[&app.config.name] == 0)
+            .collect();
+        if ready.is_empty(){
+            break
+        }
+        for app in ready{
+            done.insert(&app.config.name);
+            sorted.push(app.clone());
+            for other in apps{
+                if other.config.after.contains(&app.config.name){
+                    *pending.get_mut(&other.config.name).unwrap() -= 1;
                 }
             }
         }
     }
+
+    let (cyclic, blocked) = apps.iter()
+        .filter(|app| !done.contains(&app.config.name))
+        .cloned()
+        .partition(|app| in_cycle(apps, &done, &app.config.name));
+    (sorted, cyclic, blocked)
+}
+
+// true when following the dependencies left unsorted leads back to `name`
+fn in_cycle(apps: &Vec<Arc<App>>, done: &HashSet<&String>, name: &String) -> bool{
+    let mut seen: HashSet<&String> = HashSet::new();
+    let mut stack = vec![name];
+    while let Some(current) = stack.pop(){
+        let app = match apps.iter().find(|app| &app.config.name == current){
+            Some(app) => app,
+            None => continue,
+        };
+        for dependency in &app.config.after{
+            if dependency == name{
+                return true
+            }
+            if !done.contains(dependency) && seen.insert(dependency){
+                stack.push(dependency);
+            }
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{sync::Arc, collections::HashSet};
+
+    use tokio::sync::Mutex;
+
+    use super::{sort_apps, app::{App, AppConfig}};
+
+    fn app(name: &str, after: &[&str]) -> Arc<App>{
+        let config: AppConfig = toml::from_str(&format!("name = \"{}\"\nafter = {:?}\nkind = {{ Command = {{ program = \"true\" }} }}\n", name, after)).unwrap();
+        Arc::new(App::new("/tmp", config, None, "/tmp/unicom.sock", Arc::new(Mutex::new(HashSet::new()))))
+    }
+
+    fn names(apps: &Vec<Arc<App>>) -> Vec<&str>{
+        apps.iter().map(|app| app.config.name.as_str()).collect()
+    }
+
+    #[test]
+    fn dependencies_come_first(){
+        let apps = vec![app("web", &["api", "db"]), app("api", &["db"]), app("db", &[])];
+        let (sorted, cyclic, _) = sort_apps(&apps);
+        assert_eq!(names(&sorted), vec!["db", "api", "web"]);
+        assert!(cyclic.is_empty());
+    }
+
+    #[test]
+    fn repeated_dependency_is_not_a_cycle(){
+        let apps = vec![app("web", &["db", "db"]), app("db", &[])];
+        let (sorted, cyclic, _) = sort_apps(&apps);
+        assert_eq!(names(&sorted), vec!["db", "web"]);
+        assert!(cyclic.is_empty());
+    }
+
+    #[test]
+    fn external_nodes_do_not_constrain_the_order(){
+        let apps = vec![app("web", &["system"]), app("db", &[])];
+        let (sorted, cyclic, _) = sort_apps(&apps);
+        assert_eq!(names(&sorted), vec!["web", "db"]);
+        assert!(cyclic.is_empty());
+    }
+
+    #[test]
+    fn cycles_are_set_apart(){
+        let apps = vec![app("a", &["b"]), app("b", &["a"]), app("c", &["a"]), app("d", &[])];
+        let (sorted, cyclic, blocked) = sort_apps(&apps);
+        assert_eq!(names(&sorted), vec!["d"]);
+        assert_eq!(names(&cyclic), vec!["a", "b"]);
+        assert_eq!(names(&blocked), vec!["c"]);
+    }
 }
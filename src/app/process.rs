@@ -0,0 +1,209 @@
+use std::{process::{ExitStatus, Stdio}, os::unix::process::ExitStatusExt, fmt::Display, time::Duration, io};
+
+use chrono::Local;
+use nix::{sys::signal::{self, Signal}, unistd::Pid, errno::Errno};
+use tokio::{sync::watch, process::Command, io::{BufReader, AsyncBufReadExt}, time::{Instant, sleep, timeout_at}};
+use unicom_lib::error::{UnicomError, UnicomErrorKind};
+
+use crate::LOGGER;
+
+use super::limits::{LimitsConfig, Cgroup, limit_reason};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ExitInfo{
+    pub code: Option<i32>,
+    pub signal: Option<i32>,
+    pub time: String,
+    // set when a resource limit caused the exit
+    pub reason: Option<String>,
+}
+
+impl ExitInfo{
+    fn unknown() -> ExitInfo{
+        ExitInfo{
+            code: None,
+            signal: None,
+            time: Local::now().to_rfc3339(),
+            reason: None,
+        }
+    }
+
+    pub fn success(&self) -> bool{
+        self.code == Some(0)
+    }
+}
+
+impl From<ExitStatus> for ExitInfo{
+    fn from(status: ExitStatus) -> Self {
+        ExitInfo{
+            code: status.code(),
+            signal: status.signal(),
+            time: Local::now().to_rfc3339(),
+            reason: None,
+        }
+    }
+}
+
+impl Display for ExitInfo{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.code, self.signal){
+            (Some(code), _) => write!(f, "code {}", code)?,
+            (None, Some(signal)) => write!(f, "signal {}", signal)?,
+            (None, None) => write!(f, "unknown status")?,
+        };
+        if let Some(reason) = &self.reason{
+            write!(f, " ({})", reason)?;
+        }
+        Ok(())
+    }
+}
+
+pub struct AppProcess{
+    name: String,
+    pid : u32,
+    exit: watch::Receiver<Option<ExitInfo>>,
+}
+
+impl AppProcess {
+    pub async fn new(cmd: &mut Command, name: String, limits: &LimitsConfig) -> Result<AppProcess, UnicomError>{
+        limits.apply(cmd);
+        // own process group, stop signals the whole tree of the app
+        unsafe{
+            cmd.pre_exec(|| {
+                if libc::setpgid(0, 0) != 0{
+                    return Err(io::Error::last_os_error())
+                }
+                Ok(())
+            });
+        }
+        let cgroup = match &limits.cgroup{
+            Some(config) => Some(Cgroup::create(&name, config).await?),
+            None => None,
+        };
+        let attach = match &cgroup{
+            Some(cgroup) => Some(cgroup.attach(cmd)?),
+            None => None,
+        };
+
+        // let mut child = cmd.spawn()?;
+        let spawned = cmd.stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped()).spawn();
+
+        // the child exits before exec when it could not be moved into its cgroup
+        let attached = match attach{
+            Some(attach) => attach.wait(),
+            None => Ok(()),
+        };
+        let mut child = match (spawned, attached){
+            (Ok(child), Ok(())) => child,
+            (spawned, attached) => {
+                if let Some(cgroup) = &cgroup{
+                    cgroup.remove().await;
+                }
+                attached.map_err(|e| UnicomError::new(UnicomErrorKind::NotAllowed, &format!("app {} cgroup attach failed {:?}", name, e)))?;
+                return Err(match spawned{
+                    Err(e) => e.into(),
+                    Ok(_) => UnicomError::new(UnicomErrorKind::NotAllowed, &format!("app {} cgroup attach failed", name)),
+                })
+            },
+        };
+
+        let pid = child.id().unwrap();
+        let stdout = child.stdout.take().expect("child did not have a handle to stdout");
+        let stderr = child.stderr.take().expect("child did not have a handle to stderr");
+
+        let name1 = name.clone();
+        let name2 = name.clone();
+
+        tokio::spawn(async move{
+
+            let mut reader = BufReader::new(stdout).lines();
+            while let Ok(Some(line)) = reader.next_line().await {
+                LOGGER.app_stdout(&name1, line).await;
+            }
+        });
+
+        tokio::spawn(async move{
+            let mut reader = BufReader::new(stderr).lines();
+            while let Ok(Some(line)) = reader.next_line().await {
+                LOGGER.app_stderr(&name2, line).await;
+            }
+        });
+
+        let (exit_tx, exit) = watch::channel(None);
+        let limits = limits.clone();
+        tokio::spawn( async move {
+            let mut info = match child.wait().await{
+                Ok(status) => status.into(),
+                Err(e) => {
+                    LOGGER.error("app wait error", e.into()).await;
+                    ExitInfo::unknown()
+                },
+            };
+            info.reason = limit_reason(&info, &limits, cgroup.as_ref()).await;
+            if let Some(cgroup) = &cgroup{
+                cgroup.remove().await;
+            }
+            exit_tx.send(Some(info)).unwrap_or_default();
+        });
+
+        Ok(AppProcess{
+            pid,
+            exit,
+            name
+        })
+    }
+
+    pub fn exit(&self) -> watch::Receiver<Option<ExitInfo>>{
+        self.exit.clone()
+    }
+
+    // SIGTERM to the process group, SIGKILL for what is still alive after `stop_timeout`
+    pub async fn stop(&mut self, stop_timeout: Duration) -> Result<(), UnicomError>{
+        let group = Pid::from_raw(self.pid as i32);
+        let deadline = Instant::now() + stop_timeout;
+        signal_group(group, Signal::SIGTERM)?;
+
+        let info = match timeout_at(deadline, wait_exit(&mut self.exit)).await{
+            Ok(info) => info,
+            Err(_) => {
+                LOGGER.app_stderr(&self.name, format!("still running {:.1}s after SIGTERM, sending SIGKILL", stop_timeout.as_secs_f32())).await;
+                signal_group(group, Signal::SIGKILL)?;
+                wait_exit(&mut self.exit).await
+            },
+        };
+
+        // the leader is gone, its children get what is left of the timeout
+        while signal::killpg(group, None).is_ok(){
+            if Instant::now() >= deadline{
+                LOGGER.app_stderr(&self.name, "children still running after SIGTERM, sending SIGKILL".to_string()).await;
+                signal_group(group, Signal::SIGKILL)?;
+                break
+            }
+            sleep(Duration::from_millis(100)).await;
+        }
+
+        LOGGER.app_stdout(&self.name, format!("stoped with {}", info)).await;
+        Ok(())
+    }
+}
+
+// a group already gone is not an error
+fn signal_group(group: Pid, signal: Signal) -> Result<(), UnicomError>{
+    match signal::killpg(group, signal){
+        Ok(()) | Err(Errno::ESRCH) => Ok(()),
+        Err(e) => Err(io::Error::from(e).into()),
+    }
+}
+
+pub async fn wait_exit(exit: &mut watch::Receiver<Option<ExitInfo>>) -> ExitInfo{
+    loop{
+        if let Some(info) = &*exit.borrow(){
+            return info.clone()
+        }
+        if exit.changed().await.is_err(){
+            return ExitInfo::unknown()
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+
+use notify::{RecommendedWatcher, RecursiveMode, Watcher, Event, EventKind};
+use regex::Regex;
+use tokio::{sync::{Mutex, mpsc}, time::timeout};
+use unicom_lib::error::UnicomError;
+
+use crate::{LOGGER, SERVER};
+
+const DEFAULT_IGNORE: [&str; 7] = [".git", "__pycache__", "node_modules", "*.pyc", "*.swp", "*~", "*.log"];
+
+pub struct AppWatcher{
+    _watcher: RecommendedWatcher,
+}
+
+impl AppWatcher{
+    // watches `dir` recursively and reloads the app once no change happened for `debounce`
+    pub fn new(name: &str, dir: &str, auto_reload: Arc<Mutex<bool>>, ignore: &Vec<String>, debounce: Duration) -> Result<AppWatcher, UnicomError>{
+        let base = PathBuf::from(dir);
+        let mut patterns = Vec::new();
+        for pattern in DEFAULT_IGNORE.iter().map(|pattern| pattern.to_string()).chain(ignore.iter().cloned()){
+            patterns.push(glob_to_regex(&pattern)?);
+        }
+
+        let (tx, mut rx) = mpsc::unbounded_channel::<PathBuf>();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+            let event = match event{
+                Ok(event) => event,
+                Err(_) => return,
+            };
+            if let EventKind::Access(_) = event.kind{
+                return
+            }
+            for path in event.paths{
+                if !is_ignored(&base, &path, &patterns){
+                    tx.send(path).unwrap_or_default();
+                }
+            }
+        }).map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+        watcher.watch(Path::new(dir), RecursiveMode::Recursive)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+        let name = name.to_string();
+        tokio::spawn(async move{
+            // the channel closes when the watcher is dropped with its app
+            while let Some(mut path) = rx.recv().await{
+                loop{
+                    match timeout(debounce, rx.recv()).await{
+                        Ok(Some(next)) => path = next,
+                        Ok(None) => return,
+                        Err(_) => break,
+                    }
+                }
+
+                if !*auto_reload.lock().await{
+                    continue
+                }
+
+                LOGGER.app_stdout(&name, format!("auto reload, {} changed", path.display())).await;
+                if let Err(e) = SERVER.controller.apps.reload(&name).await{
+                    LOGGER.error(&format!("auto reload {}", name), e).await;
+                }
+            }
+        });
+
+        Ok(AppWatcher{
+            _watcher: watcher,
+        })
+    }
+}
+
+fn is_ignored(base: &Path, path: &Path, patterns: &Vec<Regex>) -> bool{
+    let relative = path.strip_prefix(base).unwrap_or(path);
+    let relative_str = relative.to_string_lossy();
+    for pattern in patterns{
+        if pattern.is_match(&relative_str){
+            return true
+        }
+        for component in relative.components(){
+            if pattern.is_match(&component.as_os_str().to_string_lossy()){
+                return true
+            }
+        }
+    }
+    false
+}
+
+fn glob_to_regex(glob: &str) -> Result<Regex, UnicomError>{
+    let mut regex = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next(){
+        match c{
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                regex.push_str(".*");
+            },
+            '*' => regex.push_str("[^/]*"),
+            '?' => regex.push_str("[^/]"),
+            c => regex.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    regex.push('$');
+    Ok(Regex::new(&regex)?)
+}
+
+#[cfg(test)]
+mod tests{
+    use std::path::Path;
+
+    use super::{glob_to_regex, is_ignored};
+
+    #[test]
+    fn globs_stop_at_slashes_unless_doubled(){
+        let single = glob_to_regex("*.log").unwrap();
+        assert!(single.is_match("app.log"));
+        assert!(!single.is_match("logs/app.log"));
+        assert!(glob_to_regex("**/*.log").unwrap().is_match("logs/old/app.log"));
+        assert!(glob_to_regex("cache?").unwrap().is_match("cache1"));
+        assert!(!glob_to_regex("a.b").unwrap().is_match("axb"));
+    }
+
+    #[test]
+    fn ignored_by_relative_path_or_component(){
+        let patterns = vec![glob_to_regex("*.log").unwrap(), glob_to_regex("node_modules").unwrap(), glob_to_regex("data/*.db").unwrap()];
+        let base = Path::new("/srv/app");
+        assert!(is_ignored(base, Path::new("/srv/app/server.log"), &patterns));
+        assert!(is_ignored(base, Path::new("/srv/app/web/node_modules/x/index.js"), &patterns));
+        assert!(is_ignored(base, Path::new("/srv/app/data/store.db"), &patterns));
+        assert!(!is_ignored(base, Path::new("/srv/app/data/old/store.db"), &patterns));
+        assert!(!is_ignored(base, Path::new("/srv/app/main.py"), &patterns));
+    }
+}
@@ -0,0 +1,263 @@
+use std::collections::HashMap;
+
+use unicom_lib::error::{UnicomError, UnicomErrorKind};
+
+use crate::{scheduler::JobConfig, log::{LogLevel, LogFormat, LogOverflow}};
+
+#[derive(Debug, Deserialize)]
+pub struct DaemonConfig{
+    #[serde(default)]
+    pub csrf: CsrfConfig,
+    #[serde(default)]
+    pub sessions: SessionConfig,
+    #[serde(default = "default_roles")]
+    pub roles: HashMap<String, RoleConfig>,
+    #[serde(default)]
+    pub access: Vec<AccessRuleConfig>,
+    // enabled/disabled state of the apps, kept across restarts of the daemon
+    #[serde(default = "default_app_state_path")]
+    pub app_state_path: String,
+    // uninstalled apps are moved here
+    #[serde(default = "default_app_archive_dir")]
+    pub app_archive_dir: String,
+    // local socket of the command line client, root only
+    #[serde(default = "default_control_path")]
+    pub control_path: String,
+    // scheduled node api calls and commands, apps declare theirs in their own config
+    #[serde(default)]
+    pub jobs: Vec<JobConfig>,
+    #[serde(default)]
+    pub logs: LogConfig,
+}
+
+impl DaemonConfig{
+    // settings which would otherwise panic once the daemon runs
+    pub fn validate(&self) -> Result<(), UnicomError>{
+        if self.sessions.expiry_interval == 0{
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, "sessions.expiry_interval must be at least 1 second"))
+        }
+        #[cfg(not(feature = "sled-store"))]
+        if self.sessions.backend == SessionBackend::Sled{
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, 
+                        "sessions.backend sled requested but unicom-daemon was built without the sled-store feature"))
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CsrfConfig{
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default)]
+    pub exempt: Vec<String>,
+    #[serde(default)]
+    pub trusted_origins: Vec<String>,
+}
+
+impl Default for CsrfConfig{
+    fn default() -> Self {
+        CsrfConfig{
+            enabled: true,
+            exempt: Vec::new(),
+            trusted_origins: Vec::new(),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionBackend{
+    Json,
+    Sled,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SessionConfig{
+    #[serde(default = "default_session_backend")]
+    pub backend: SessionBackend,
+    // overrides `session_path`, the sled backend needs a directory
+    pub path: Option<String>,
+    // seconds between two purges of expired sessions
+    #[serde(default = "default_expiry_interval")]
+    pub expiry_interval: u64,
+    // seconds to wait for other changes before writing the store
+    #[serde(default = "default_save_delay")]
+    pub save_delay: u64,
+    // seconds of inactivity before a session expires
+    #[serde(default = "default_session_lifetime")]
+    pub lifetime: u64,
+}
+
+impl Default for SessionConfig{
+    fn default() -> Self {
+        SessionConfig{
+            backend: default_session_backend(),
+            path: None,
+            expiry_interval: default_expiry_interval(),
+            save_delay: default_save_delay(),
+            lifetime: default_session_lifetime(),
+        }
+    }
+}
+
+fn default_session_backend() -> SessionBackend{
+    SessionBackend::Json
+}
+
+fn default_expiry_interval() -> u64{
+    60
+}
+
+fn default_save_delay() -> u64{
+    2
+}
+
+fn default_session_lifetime() -> u64{
+    5 * 7 * 24 * 3600
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LogConfig{
+    // app, unicom and http logs are also written on disk
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(default = "default_log_dir")]
+    pub dir: String,
+    // bytes before a file is rotated
+    #[serde(default = "default_log_max_size")]
+    pub max_size: u64,
+    // seconds before a file is rotated, whatever its size
+    pub rotate_interval: Option<u64>,
+    // rotated files kept per log
+    #[serde(default = "default_log_retention")]
+    pub retention: usize,
+    // days a rotated file is kept
+    pub max_age: Option<u64>,
+    // gzip the rotated files
+    #[serde(default)]
+    pub compress: bool,
+    // records kept in memory per app, apps can lower or raise it in their config
+    #[serde(default = "default_log_memory_lines")]
+    pub memory_lines: usize,
+    // bytes of messages kept in memory per app
+    #[serde(default = "default_log_memory_bytes")]
+    pub memory_bytes: usize,
+    // minimum level of the daemon messages
+    #[serde(default = "default_log_level")]
+    pub level: LogLevel,
+    // minimum level per module (server, apps, http, unicom, ...)
+    #[serde(default)]
+    pub modules: HashMap<String, LogLevel>,
+    // output of the daemon, text, json or journald
+    #[serde(default = "default_log_format")]
+    pub format: LogFormat,
+    // messages waiting for the logger, the request paths drop theirs when it is full
+    #[serde(default = "default_log_queue")]
+    pub queue: usize,
+    // wait or drop the app output when the queue is full
+    #[serde(default = "default_log_app_overflow")]
+    pub app_overflow: LogOverflow,
+}
+
+impl Default for LogConfig{
+    fn default() -> Self {
+        LogConfig{
+            enabled: true,
+            dir: default_log_dir(),
+            max_size: default_log_max_size(),
+            rotate_interval: None,
+            retention: default_log_retention(),
+            max_age: None,
+            compress: false,
+            memory_lines: default_log_memory_lines(),
+            memory_bytes: default_log_memory_bytes(),
+            level: default_log_level(),
+            modules: HashMap::new(),
+            format: default_log_format(),
+            queue: default_log_queue(),
+            app_overflow: default_log_app_overflow(),
+        }
+    }
+}
+
+fn default_log_dir() -> String{
+    "/var/unicom/logs".to_string()
+}
+
+fn default_log_max_size() -> u64{
+    10 * 1024 * 1024
+}
+
+fn default_log_retention() -> usize{
+    7
+}
+
+fn default_log_level() -> LogLevel{
+    LogLevel::Info
+}
+
+fn default_log_format() -> LogFormat{
+    LogFormat::Text
+}
+
+fn default_log_queue() -> usize{
+    4096
+}
+
+fn default_log_app_overflow() -> LogOverflow{
+    LogOverflow::Wait
+}
+
+fn default_log_memory_lines() -> usize{
+    300
+}
+
+fn default_log_memory_bytes() -> usize{
+    256 * 1024
+}
+
+#[derive(Debug, Deserialize, Clone, Default)]
+pub struct RoleConfig{
+    #[serde(default)]
+    pub permissions: Vec<String>,
+    #[serde(default)]
+    pub users: Vec<String>,
+    #[serde(default)]
+    pub groups: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct AccessRuleConfig{
+    pub path: String,
+    pub permission: Option<String>,
+    #[serde(default)]
+    pub roles: Vec<String>,
+}
+
+// without any role configured, members of sudo keep their admin rights
+fn default_roles() -> HashMap<String, RoleConfig>{
+    let mut roles = HashMap::new();
+    roles.insert("admin".to_string(), RoleConfig{
+        permissions: vec!["*".to_string()],
+        users: Vec::new(),
+        groups: vec!["sudo".to_string()],
+    });
+    roles
+}
+
+fn default_app_state_path() -> String{
+    "/var/unicom/apps.json".to_string()
+}
+
+fn default_app_archive_dir() -> String{
+    "/var/unicom/archive".to_string()
+}
+
+fn default_control_path() -> String{
+    "/var/unicom/control.sock".to_string()
+}
+
+fn default_true() -> bool{
+    true
+}
@@ -0,0 +1,112 @@
+use serde_json::{Map, json, Value};
+use tokio::{net::UnixStream, io::{BufReader, AsyncBufReadExt, AsyncWriteExt}};
+
+use crate::DAEMON_CONFIG;
+
+use super::{ControlRequest, ControlResponse};
+
+const USAGE: &str = "usage: unicom-daemon [apps | app install <archive|git dir> | app uninstall <name> [--delete]
+                      | logs [-f] [--source app|unicom|http] [--level <level>] [--search <text>] [app...]]";
+
+// command line client of the control socket, returns the exit code
+pub async fn run(args: Vec<String>) -> i32{
+    let args: Vec<&str> = args.iter().map(|arg| arg.as_str()).collect();
+    let request = match args.as_slice(){
+        ["apps"] => request("apps", Map::new()),
+        ["app", "install", path] => {
+            // the daemon does not share our working directory
+            let path = std::fs::canonicalize(path).map(|path| path.display().to_string()).unwrap_or(path.to_string());
+            request("app_install", args_map(json!({"path": path})))
+        },
+        ["app", "uninstall", name] => request("app_uninstall", args_map(json!({"name": name}))),
+        ["app", "uninstall", name, "--delete"] => request("app_uninstall", args_map(json!({"name": name, "delete": true}))),
+        ["logs", options @ ..] => match logs_request(options){
+            Some(request) => request,
+            None => {
+                eprintln!("{}", USAGE);
+                return 2
+            },
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2
+        },
+    };
+
+    match send(&request).await{
+        Ok(code) => code,
+        Err(e) => {
+            eprintln!("control socket {} error: {}", &DAEMON_CONFIG.control_path, e);
+            1
+        },
+    }
+}
+
+// `logs -f` follows every given app, without it the last records of one source are printed
+fn logs_request(options: &[&str]) -> Option<ControlRequest>{
+    let mut args = Map::new();
+    let mut apps = Vec::new();
+    let mut follow = false;
+    let mut options = options.iter();
+    while let Some(option) = options.next(){
+        match *option{
+            "-f" | "--follow" => follow = true,
+            "--source" | "--level" | "--search" => {
+                args.insert(option.trim_start_matches('-').to_string(), json!(options.next()?));
+            },
+            app if !app.starts_with('-') => apps.push(app.to_string()),
+            _ => return None,
+        }
+    }
+
+    if follow{
+        args.insert("app".to_string(), json!(apps));
+        return Some(request("logs_follow", args))
+    }
+    match apps.len(){
+        0 => (),
+        1 => {
+            args.insert("app".to_string(), json!(apps[0]));
+        },
+        _ => return None,
+    }
+    Some(request("logs", args))
+}
+
+fn request(command: &str, args: Map<String, Value>) -> ControlRequest{
+    ControlRequest{
+        command: command.to_string(),
+        args,
+    }
+}
+
+fn args_map(value: Value) -> Map<String, Value>{
+    match value{
+        Value::Object(map) => map,
+        _ => Map::new(),
+    }
+}
+
+// prints every response until the daemon closes the connection
+async fn send(request: &ControlRequest) -> std::io::Result<i32>{
+    let stream = UnixStream::connect(&DAEMON_CONFIG.control_path).await?;
+    let (reader, mut writer) = stream.into_split();
+    let mut data = serde_json::to_string(request)?;
+    data.push('\n');
+    writer.write_all(data.as_bytes()).await?;
+    writer.shutdown().await?;
+
+    let mut code = 0;
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await?{
+        match serde_json::from_str::<ControlResponse>(&line)?{
+            ControlResponse::Ok(Value::String(value)) => println!("{}", value),
+            ControlResponse::Ok(value) => println!("{}", serde_json::to_string_pretty(&value)?),
+            ControlResponse::Error(e) => {
+                eprintln!("{}", e);
+                code = 1;
+            },
+        }
+    }
+    Ok(code)
+}
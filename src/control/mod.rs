@@ -0,0 +1,123 @@
+use std::{sync::Arc, os::unix::fs::PermissionsExt};
+
+use serde_json::{Map, Value, json};
+use tokio::{net::{UnixListener, UnixStream, unix::OwnedWriteHalf}, io::{BufReader, AsyncBufReadExt, AsyncWriteExt}, sync::broadcast::error::RecvError};
+use unicom_lib::error::{UnicomError, UnicomErrorKind};
+
+use crate::{server::controller::Controller, log::{query::LogQuery, tail::{LogFilter, LogEvent}}, LOGGER};
+
+pub mod client;
+
+// one json line per request, answered by one json line per response
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ControlRequest{
+    pub command: String,
+    #[serde(default)]
+    pub args: Map<String, Value>,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+pub enum ControlResponse{
+    Ok(Value),
+    Error(String),
+}
+
+pub async fn control_server(path: String, controller: Arc<Controller>){
+    std::fs::remove_file(&path).unwrap_or_default();
+    let listener = match UnixListener::bind(&path){
+        Ok(listener) => listener,
+        Err(e) => {
+            LOGGER.error("control socket bind error", e.into()).await;
+            return
+        },
+    };
+    // the socket gives full control over the apps, root only
+    if let Err(e) = std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)){
+        LOGGER.error("control socket permissions error", e.into()).await;
+        return
+    }
+
+    loop{
+        if let Ok((stream, _addr)) = listener.accept().await {
+            let controller = controller.clone();
+            tokio::spawn(async move{
+                if let Err(e) = handle(stream, controller).await{
+                    LOGGER.error("control connection error", e).await;
+                }
+            });
+        }
+    }
+}
+
+async fn handle(stream: UnixStream, controller: Arc<Controller>) -> Result<(), UnicomError>{
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    while let Some(line) = lines.next_line().await?{
+        let response = match serde_json::from_str::<ControlRequest>(&line){
+            // takes over the connection
+            Ok(request) if request.command == "logs_follow" => match follow(&request, &mut writer).await{
+                Ok(()) => return Ok(()),
+                Err(e) => ControlResponse::Error(format!("{:?}", e)),
+            },
+            Ok(request) => match execute(&controller, &request).await{
+                Ok(value) => ControlResponse::Ok(value),
+                Err(e) => ControlResponse::Error(format!("{:?}", e)),
+            },
+            Err(e) => ControlResponse::Error(format!("invalid request {:?}", e)),
+        };
+        write_response(&mut writer, &response).await?;
+    }
+    Ok(())
+}
+
+async fn write_response(writer: &mut OwnedWriteHalf, response: &ControlResponse) -> Result<(), UnicomError>{
+    let mut data = serde_json::to_string(response)?;
+    data.push('\n');
+    writer.write_all(data.as_bytes()).await?;
+    Ok(())
+}
+
+// streams the matching log records until the client goes away
+async fn follow(request: &ControlRequest, writer: &mut OwnedWriteHalf) -> Result<(), UnicomError>{
+    let filter = LogFilter::from_parameters(&request.args)?;
+    let mut events = LOGGER.subscribe();
+    loop{
+        let response = match events.recv().await{
+            Ok(event) if filter.matches(&event) => ControlResponse::Ok(Value::String(event.to_string())),
+            Ok(_) => continue,
+            Err(RecvError::Lagged(count)) => ControlResponse::Error(format!("{} log records lost", count)),
+            Err(RecvError::Closed) => return Ok(()),
+        };
+        if write_response(writer, &response).await.is_err(){
+            return Ok(())
+        }
+    }
+}
+
+async fn execute(controller: &Arc<Controller>, request: &ControlRequest) -> Result<Value, UnicomError>{
+    match request.command.as_str(){
+        "apps" => Ok(json!(controller.apps.status().await?)),
+        "app_install" => Ok(json!(controller.apps.install(arg_str(request, "path")?).await?)),
+        "app_uninstall" => {
+            let delete = request.args.get("delete").and_then(|delete| delete.as_bool()).unwrap_or(false);
+            Ok(json!(controller.apps.uninstall(arg_str(request, "name")?, delete).await?))
+        },
+        "logs" => {
+            let query = LogQuery::from_parameters(&request.args)?;
+            let (source, app) = (query.source.clone(), query.app.clone());
+            let page = LOGGER.query(query).await?;
+            let lines: Vec<String> = page.records.into_iter()
+                .map(|record| LogEvent{ source: source.clone(), app: app.clone(), record }.to_string())
+                .collect();
+            Ok(Value::String(lines.join("\n")))
+        },
+        command => Err(UnicomError::new(UnicomErrorKind::NotFound, &format!("unknown command {}", command))),
+    }
+}
+
+fn arg_str<'a>(request: &'a ControlRequest, name: &str) -> Result<&'a str, UnicomError>{
+    match request.args.get(name).and_then(|value| value.as_str()){
+        Some(value) => Ok(value),
+        None => Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("argument {} missing", name))),
+    }
+}
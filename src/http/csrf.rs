@@ -0,0 +1,208 @@
+use futures::{stream, StreamExt};
+use hyper::{http::request, Body, Method, Uri, body::Bytes, header::{CONTENT_TYPE, HOST, ORIGIN, REFERER}};
+use regex::Regex;
+use unicom_lib::error::{UnicomError, UnicomErrorKind};
+
+use crate::config::CsrfConfig;
+
+use super::session::Session;
+
+pub const CSRF_HEADER: &str = "x-csrf-token";
+// form field of urlencoded and multipart bodies
+pub const CSRF_PARAMETER: &str = "csrf_token";
+// node tag listing, comma separated, the endpoint regexes of the node which skip the check
+pub const CSRF_EXEMPT_TAG: &str = "csrf_exempt";
+// the form field is looked for in the first bytes of the body only, the rest is streamed untouched
+const CSRF_BODY_LIMIT: usize = 1024 * 1024;
+
+pub struct CsrfGuard{
+    enabled: bool,
+    exempt: Vec<Regex>,
+    trusted_origins: Vec<String>,
+}
+
+impl CsrfGuard{
+    pub fn new(config: &CsrfConfig) -> CsrfGuard{
+        CsrfGuard{
+            enabled: config.enabled,
+            exempt: config.exempt.iter()
+                .map(|regex| Regex::new(&format!("^{}$", regex)).expect("invalid csrf exempt regex"))
+                .collect(),
+            trusted_origins: config.trusted_origins.clone(),
+        }
+    }
+
+    // the body is given back as it may have been read to find the token
+    pub async fn verify(&self, parts: &request::Parts, body: Body, session: &Session, endpoint_exempt: bool) -> Result<Body, UnicomError>{
+        if !self.enabled || is_safe_method(&parts.method) || endpoint_exempt{
+            return Ok(body)
+        }
+
+        let path = parts.uri.path();
+        if self.exempt.iter().any(|regex| regex.is_match(path)){
+            return Ok(body)
+        }
+
+        self.verify_origin(parts)?;
+
+        let (token, body) = match parts.headers.get(CSRF_HEADER){
+            Some(token) => (token.to_str().ok().map(|token| token.to_string()), body),
+            None => body_token(parts, body).await?,
+        };
+
+        match token{
+            Some(token) if session.check_csrf_token(&token) => Ok(body),
+            _ => Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("csrf token invalid for {} {}", parts.method, path))),
+        }
+    }
+
+    fn verify_origin(&self, parts: &request::Parts) -> Result<(), UnicomError>{
+        let source = match parts.headers.get(ORIGIN).or(parts.headers.get(REFERER)){
+            Some(source) => source.to_str().unwrap_or(""),
+            // no Origin nor Referer (privacy settings, old clients), the token alone decides
+            None => return Ok(()),
+        };
+
+        let source_authority = match source.parse::<Uri>(){
+            Ok(uri) => uri.authority().map(|authority| authority.as_str().to_lowercase()),
+            Err(_) => None,
+        };
+
+        let source_authority = match source_authority{
+            Some(authority) => authority,
+            None => return Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("csrf origin invalid {}", source))),
+        };
+
+        if let Some(host) = parts.headers.get(HOST){
+            if host.to_str().unwrap_or("").to_lowercase() == source_authority{
+                return Ok(())
+            }
+        }
+
+        for origin in &self.trusted_origins{
+            if origin.to_lowercase() == source_authority || origin.to_lowercase() == source.to_lowercase(){
+                return Ok(())
+            }
+        }
+
+        Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("csrf cross origin request from {}", source)))
+    }
+}
+
+pub fn is_safe_method(method: &Method) -> bool{
+    method == Method::GET || method == Method::HEAD || method == Method::OPTIONS
+}
+
+// true when the endpoint regexes of the csrf_exempt tag match the whole path
+pub fn tag_exempt(tag: &str, path: &str) -> bool{
+    tag.split(',')
+        .map(|regex| regex.trim())
+        .filter(|regex| regex.len() > 0)
+        .any(|regex| Regex::new(&format!("^{}$", regex)).map(|regex| regex.is_match(path)).unwrap_or(false))
+}
+
+// csrf_token field of an urlencoded or multipart body, the read bytes are put back in front of the body
+async fn body_token(parts: &request::Parts, mut body: Body) -> Result<(Option<String>, Body), UnicomError>{
+    let content_type = parts.headers.get(CONTENT_TYPE).and_then(|value| value.to_str().ok()).unwrap_or("");
+    let boundary = match content_type.split(';').next().unwrap_or("").trim().to_lowercase().as_str(){
+        "application/x-www-form-urlencoded" => None,
+        "multipart/form-data" => match multipart_boundary(content_type){
+            Some(boundary) => Some(boundary),
+            None => return Ok((None, body)),
+        },
+        _ => return Ok((None, body)),
+    };
+
+    let mut buffer = Vec::new();
+    let mut complete = false;
+    let mut token = None;
+    while token.is_none() && buffer.len() < CSRF_BODY_LIMIT{
+        match body.next().await{
+            Some(Ok(chunk)) => buffer.extend_from_slice(&chunk),
+            Some(Err(e)) => return Err(UnicomError::new(UnicomErrorKind::InputInvalid, &format!("read body error {:?}", e))),
+            None => complete = true,
+        }
+        token = match &boundary{
+            Some(boundary) => multipart_field(&buffer, boundary, CSRF_PARAMETER),
+            None => form_field(&buffer, CSRF_PARAMETER, complete),
+        };
+        if complete{
+            break
+        }
+    }
+
+    let head = stream::iter(vec![Ok::<_, hyper::Error>(Bytes::from(buffer))]);
+    Ok((token, Body::wrap_stream(head.chain(body))))
+}
+
+fn form_field(buffer: &[u8], name: &str, complete: bool) -> Option<String>{
+    let content = String::from_utf8_lossy(buffer);
+    let mut fields: Vec<&str> = content.split('&').collect();
+    // the last field may still be cut by the next chunk
+    if !complete{
+        fields.pop();
+    }
+    fields.iter().find_map(|field| field.strip_prefix(name).and_then(|rest| rest.strip_prefix('=')))
+        .map(|value| value.trim().to_string())
+}
+
+fn multipart_boundary(content_type: &str) -> Option<String>{
+    content_type.split(';')
+        .find_map(|param| param.trim().strip_prefix("boundary="))
+        .map(|boundary| boundary.trim_matches('"').to_string())
+        .filter(|boundary| boundary.len() > 0)
+}
+
+fn multipart_field(buffer: &[u8], boundary: &str, name: &str) -> Option<String>{
+    let delimiter = format!("\r\n--{}", boundary);
+    let disposition = format!("name=\"{}\"", name);
+    // the first delimiter opens the body without the leading crlf
+    let mut start = find(buffer, &delimiter.as_bytes()[2..], 0)? + delimiter.len() - 2;
+    loop{
+        let headers_end = find(buffer, b"\r\n\r\n", start)?;
+        let value_end = find(buffer, delimiter.as_bytes(), headers_end + 4)?;
+        let headers = String::from_utf8_lossy(&buffer[start..headers_end]).to_lowercase();
+        if headers.split(';').any(|param| param.trim() == disposition){
+            return String::from_utf8(buffer[headers_end + 4..value_end].to_vec()).ok().map(|value| value.trim().to_string())
+        }
+        start = value_end + delimiter.len();
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize>{
+    if from > haystack.len(){
+        return None
+    }
+    haystack[from..].windows(needle.len()).position(|window| window == needle).map(|index| index + from)
+}
+
+#[cfg(test)]
+mod tests{
+    use super::{form_field, multipart_boundary, multipart_field, tag_exempt};
+
+    #[test]
+    fn form_token_is_read_once_the_field_is_complete(){
+        assert_eq!(form_field(b"a=1&csrf_token=abc&b=2", "csrf_token", false), Some("abc".to_string()));
+        assert_eq!(form_field(b"a=1&csrf_token=ab", "csrf_token", false), None);
+        assert_eq!(form_field(b"a=1&csrf_token=abc", "csrf_token", true), Some("abc".to_string()));
+        assert_eq!(form_field(b"csrf_token_old=x", "csrf_token", true), None);
+    }
+
+    #[test]
+    fn multipart_token_is_read_from_its_part(){
+        let boundary = multipart_boundary("multipart/form-data; boundary=\"XyZ\"").unwrap();
+        assert_eq!(boundary, "XyZ");
+        let body = b"--XyZ\r\nContent-Disposition: form-data; name=\"file\"; filename=\"csrf_token\"\r\n\r\ndata\r\n\
+                     --XyZ\r\nContent-Disposition: form-data; name=\"csrf_token\"\r\n\r\nabc\r\n--XyZ--\r\n";
+        assert_eq!(multipart_field(body, &boundary, "csrf_token"), Some("abc".to_string()));
+        assert_eq!(multipart_field(&body[..body.len() - 14], &boundary, "csrf_token"), None);
+    }
+
+    #[test]
+    fn tag_lists_endpoint_regexes(){
+        assert!(tag_exempt("/hook/.*, /api/ping", "/api/ping"));
+        assert!(tag_exempt("/hook/.*", "/hook/github"));
+        assert!(!tag_exempt("/hook", "/hook/github"));
+        assert!(!tag_exempt("", "/"));
+    }
+}
@@ -2,19 +2,24 @@ use std::sync::Arc;
 
 use futures::{StreamExt, TryStreamExt};
 use tokio::{fs::File, io};
-use unicom_lib::{error::{UnicomError, UnicomErrorKind}, node::api::{ApiMethod, ValueKind}};
+use unicom_lib::{error::{UnicomError, UnicomErrorKind}, node::{NodeConfig, api::{ApiMethod, ValueKind}}};
 use uuid::Uuid;
 
 
 use hyper::{http::request, Body, header::{CONTENT_LENGTH, CONTENT_TYPE}};
 use serde_json::{Map, json, Value};
 
+use crate::{log::LogLevel, LOGGER};
+
 use self::{input_file::InputFile, session::Session};
 
 pub mod router;
 pub mod render;
 pub mod input_file;
 pub mod session;
+pub mod session_store;
+pub mod csrf;
+pub mod roles;
 
 pub fn parse_parameters(parts: &request::Parts) -> Result<Map<String,Value>, UnicomError>{
     let mut raw_parameters = Map::new();
@@ -99,11 +104,34 @@ fn to_tokio_async_read(r: impl futures::io::AsyncRead) -> impl tokio::io::AsyncR
     tokio_util::compat::FuturesAsyncReadCompatExt::compat(r)
 }
 
-pub fn add_http(api: &ApiMethod, parameters: &mut Map<String, Value>, url: Vec<String>, session: &Arc<Session>, input: Option<Value>){
+// reserved parameter name, unicom-lib has no session data kind: a SessionID parameter named exactly
+// `session_data` receives the session data of the node owning the api instead of the session id,
+// a node declaring `session_data` with another kind is refused
+pub const SESSION_DATA_PARAMETER: &str = "session_data";
+// reserved, always set by the daemon to the node making the request, a value sent by the client is replaced
+pub const CALLER_PARAMETER: &str = "caller";
+
+pub fn check_reserved(config: &NodeConfig) -> Result<(), UnicomError>{
+    for api in &config.apis{
+        for method in &api.methods{
+            for parameter in &method.parameters{
+                if parameter.name == SESSION_DATA_PARAMETER && !matches!(parameter.kind, ValueKind::SessionID){
+                    return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, 
+                        &format!("node {} api {} parameter {} is reserved for the session data and must be of kind SessionID", config.name, api.name, parameter.name)))
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn add_http(api: &ApiMethod, parameters: &mut Map<String, Value>, url: Vec<String>, session: &Arc<Session>, input: Option<Value>, node_name: &str, caller: &str){
+    parameters.insert(CALLER_PARAMETER.to_string(), json!(caller));
+
     let mut input_name = None;
     
     for parameter in &api.parameters{
-        println!("{}", parameter.name);
+        LOGGER.log_now(LogLevel::Trace, "http", format!("parameter {}", parameter.name));
         match parameter.kind {
             ValueKind::Url(index) => {
                 if index < url.len() && url[index].len() > 0{
@@ -113,6 +141,10 @@ pub fn add_http(api: &ApiMethod, parameters: &mut Map<String, Value>, url: Vec<S
             ValueKind::Input => {
                 input_name = Some(parameter.name.clone())
             },
+            // ValueKind comes from unicom-lib, session data reuse the SessionID kind
+            ValueKind::SessionID if parameter.name == SESSION_DATA_PARAMETER => {
+                parameters.insert(parameter.name.clone(), Value::Object(session.get_data(node_name)));
+            }
             ValueKind::SessionID => {
                 parameters.insert(parameter.name.clone(), json!(session.id));
             }
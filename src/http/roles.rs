@@ -0,0 +1,159 @@
+use std::collections::HashMap;
+
+use regex::Regex;
+use unicom_lib::error::{UnicomError, UnicomErrorKind};
+
+use crate::config::{RoleConfig, AccessRuleConfig};
+
+use super::session::Session;
+
+pub struct RoleManager{
+    roles: HashMap<String, RoleConfig>,
+}
+
+impl RoleManager{
+    pub fn new(roles: &HashMap<String, RoleConfig>) -> RoleManager{
+        RoleManager{
+            roles: roles.clone(),
+        }
+    }
+
+    // returns the roles given to the user or one of its groups and the merged permissions
+    pub fn resolve(&self, user_name: &str, groups: &Vec<String>) -> (Vec<String>, Vec<String>){
+        let mut roles = Vec::new();
+        let mut permissions = Vec::new();
+        for (name, role) in &self.roles{
+            let member = role.users.iter().any(|user| user == user_name)
+                            || role.groups.iter().any(|group| groups.contains(group));
+            if !member{
+                continue
+            }
+            roles.push(name.clone());
+            for permission in &role.permissions{
+                if !permissions.contains(permission){
+                    permissions.push(permission.clone());
+                }
+            }
+        }
+        roles.sort();
+        (roles, permissions)
+    }
+}
+
+// `*` grants everything, `media.*` grants `media.view` and `media.kids.view`
+pub fn permission_match(granted: &str, permission: &str) -> bool{
+    if granted == "*" || granted == permission{
+        return true
+    }
+    match granted.strip_suffix(".*"){
+        Some(prefix) => permission.starts_with(prefix) && permission[prefix.len()..].starts_with('.'),
+        None => false,
+    }
+}
+
+struct AccessRule{
+    regex: Regex,
+    permission: Option<String>,
+    roles: Vec<String>,
+}
+
+pub struct AccessRules{
+    rules: Vec<AccessRule>,
+}
+
+impl AccessRules{
+    pub fn new(config: &Vec<AccessRuleConfig>) -> AccessRules{
+        AccessRules{
+            rules: config.iter().map(|rule| AccessRule{
+                regex: Regex::new(&format!("^{}$", rule.path)).expect("invalid access rule regex"),
+                permission: rule.permission.clone(),
+                roles: rule.roles.clone(),
+            }).collect(),
+        }
+    }
+
+    // every rule matching the path must be satisfied
+    pub fn verify(&self, path: &str, session: &Session) -> Result<(), UnicomError>{
+        for rule in &self.rules{
+            if !rule.regex.is_match(path){
+                continue
+            }
+            if let Some(permission) = &rule.permission{
+                if !session.has_permission(permission){
+                    return Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("permission {} required for {}", permission, path)))
+                }
+            }
+            if rule.roles.len() > 0 && !rule.roles.iter().any(|role| session.has_role(role)){
+                return Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("role {:?} required for {}", rule.roles, path)))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{collections::HashMap, sync::Arc};
+
+    use serde_json::json;
+
+    use crate::{config::{RoleConfig, AccessRuleConfig}, http::session::{Session, SessionJson}};
+
+    use super::{permission_match, RoleManager, AccessRules};
+
+    fn session(user: Option<(&str, &[&str], &[&str])>) -> Arc<Session>{
+        let user = user.map(|(name, roles, permissions)| json!({"name": name, "level": "Normal", "roles": roles, "permissions": permissions}));
+        let session: SessionJson = serde_json::from_value(json!({"id": "1", "user": user, "expire": "Tue, 1 Jul 2003 10:52:37 +0200"})).unwrap();
+        session.into()
+    }
+
+    fn rule(path: &str, permission: Option<&str>, roles: &[&str]) -> AccessRuleConfig{
+        AccessRuleConfig{
+            path: path.to_string(),
+            permission: permission.map(|permission| permission.to_string()),
+            roles: roles.iter().map(|role| role.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn permissions_match_exactly_or_by_wildcard(){
+        assert!(permission_match("media.view", "media.view"));
+        assert!(!permission_match("media.view", "media.edit"));
+        assert!(permission_match("*", "apps.manage"));
+        assert!(permission_match("media.*", "media.view"));
+        assert!(permission_match("media.*", "media.kids.view"));
+        assert!(!permission_match("media.*", "media"));
+        assert!(!permission_match("media.*", "mediaplayer.view"));
+    }
+
+    #[test]
+    fn roles_come_from_users_and_groups(){
+        let mut roles = HashMap::new();
+        roles.insert("kid".to_string(), RoleConfig{ permissions: vec!["media.view".to_string()], users: vec!["alice".to_string()], groups: Vec::new() });
+        roles.insert("staff".to_string(), RoleConfig{ permissions: vec!["media.*".to_string(), "media.view".to_string()], users: Vec::new(), groups: vec!["staff".to_string()] });
+        let manager = RoleManager::new(&roles);
+        let (roles, mut permissions) = manager.resolve("alice", &vec!["staff".to_string()]);
+        permissions.sort();
+        assert_eq!(roles, vec!["kid", "staff"]);
+        // merged once
+        assert_eq!(permissions, vec!["media.*", "media.view"]);
+        assert_eq!(manager.resolve("alice", &Vec::new()).0, vec!["kid"]);
+        // a user without role gets nothing
+        assert_eq!(manager.resolve("bob", &Vec::new()), (Vec::new(), Vec::new()));
+    }
+
+    #[test]
+    fn access_rules_need_the_permission_and_one_of_the_roles(){
+        let rules = AccessRules::new(&vec![rule("/admin/.*", Some("admin.view"), &[]), rule("/kids/.*", None, &["kid", "parent"])]);
+        let admin = session(Some(("root", &["admin"], &["admin.*"])));
+        let kid = session(Some(("alice", &["kid"], &["media.view"])));
+        let anonymous = session(None);
+
+        assert!(rules.verify("/admin/apps", &admin).is_ok());
+        assert!(rules.verify("/admin/apps", &kid).is_err());
+        assert!(rules.verify("/admin/apps", &anonymous).is_err());
+        assert!(rules.verify("/kids/cartoons", &kid).is_ok());
+        assert!(rules.verify("/kids/cartoons", &admin).is_err());
+        assert!(rules.verify("/public", &anonymous).is_ok());
+    }
+}
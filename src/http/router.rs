@@ -50,13 +50,17 @@ impl Router{
     }
 
     pub async fn remove(&self, node: &Arc<Node>) -> Result<(), UnicomError>{
+        self.remove_routes(&node.name).await;
+        Ok(())
+    }
+
+    pub async fn remove_routes(&self, node_name: &str){
         let mut routes = self.routes.lock().await;
         for index in (0..routes.len()).rev(){
-            if node.name == routes[index].node{
+            if node_name == routes[index].node{
                 routes.swap_remove(index);
             }
         }
-        Ok(())
     }
    
 }
@@ -1,48 +1,165 @@
-use std::{sync::Arc, ffi::CString};
+use std::{sync::Arc, ffi::CString, collections::{HashMap, HashSet}};
 
 use chrono::{DateTime, Utc, Duration};
 use hyper::{header::COOKIE, Request, Body};
 use rand::Rng;
 use regex::Regex;
-use tokio::fs::OpenOptions;
-use tokio::sync::Mutex;
-use tokio::io::AsyncWriteExt;
+use serde_json::{Map, Value};
+use tokio::{sync::{Mutex, Notify}, time::sleep};
 use unicom_lib::error::{UnicomError, UnicomErrorKind};
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+use crate::{config::SessionConfig, LOGGER};
+
+use super::{session_store::{SessionStore, new_store}, roles::{RoleManager, permission_match}};
+
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub enum UserLevel {
     Admin,
     Root,
     Normal,
 }
 
-#[derive(Debug, Deserialize, Serialize, Clone)]
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq)]
 pub struct User{
     name: String,
+    // kept for nodes and templates written before roles, derived from the permissions
     level: UserLevel,
+    #[serde(default)]
+    roles: Vec<String>,
+    #[serde(default)]
+    permissions: Vec<String>,
+    // unix groups, the roles are resolved again from them on every request
+    #[serde(default)]
+    groups: Vec<String>,
+}
+
+impl User{
+    pub fn has_permission(&self, permission: &str) -> bool{
+        match self.level{
+            UserLevel::Admin | UserLevel::Root => return true,
+            UserLevel::Normal => (),
+        };
+        self.permissions.iter().any(|granted| permission_match(granted, permission))
+    }
+
+    pub fn has_role(&self, role: &str) -> bool{
+        self.roles.iter().any(|name| name == role)
+    }
 }
 
 pub struct Session{
     pub id: String,
+    pub csrf_token: String,
     user: std::sync::Mutex<Option<User>>,
+    data: std::sync::Mutex<HashMap<String, Map<String, Value>>>,
+    created: DateTime<Utc>,
+    activity: std::sync::Mutex<Activity>,
+}
+
+#[derive(Debug, Clone)]
+struct Activity{
+    last_seen: DateTime<Utc>,
+    // last time the expiration slid, last_seen moves on every request
+    last_refresh: DateTime<Utc>,
     expire: DateTime<Utc>,
+    ip: Option<String>,
+    user_agent: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SessionInfo{
+    id: String,
+    user: Option<String>,
+    created: String,
+    last_seen: String,
+    expire: String,
+    ip: Option<String>,
+    user_agent: Option<String>,
 }
 
 impl Session{
-    fn new() -> Session{
+    fn new(lifetime: Duration) -> Session{
+        let now = Utc::now();
         Session{
             id: format!("{:x}", rand::thread_rng().gen::<u64>()),
+            csrf_token: gen_csrf_token(),
             user: std::sync::Mutex::new(None),
-            expire: Utc::now().checked_add_signed(Duration::weeks(5)).unwrap(),
+            data: std::sync::Mutex::new(HashMap::new()),
+            created: now,
+            activity: std::sync::Mutex::new(Activity{
+                last_seen: now,
+                last_refresh: now,
+                expire: now.checked_add_signed(lifetime).unwrap(),
+                ip: None,
+                user_agent: None,
+            }),
         }
     }
 
     fn has_expire(&self) -> bool{
-        self.expire < Utc::now()
+        self.activity.lock().unwrap().expire < Utc::now()
     }
 
     pub fn gen_cookies(&self) -> String{
-        format!("sessionID={}; Expires={}; SameSite=Strict", self.id, self.expire.to_rfc2822())
+        format!("sessionID={}; Expires={}; SameSite=Strict", self.id, self.activity.lock().unwrap().expire.to_rfc2822())
+    }
+
+    // slides the expiration, returns true when the change is worth persisting and sending a new cookie
+    fn touch(&self, lifetime: Duration, ip: Option<String>, user_agent: Option<String>) -> bool{
+        let now = Utc::now();
+        let mut activity = self.activity.lock().unwrap();
+        let refresh = now.signed_duration_since(activity.last_refresh) > Duration::minutes(1) 
+                        || activity.ip != ip || activity.user_agent != user_agent;
+        activity.last_seen = now;
+        if refresh{
+            activity.last_refresh = now;
+            activity.expire = now.checked_add_signed(lifetime).unwrap();
+            activity.ip = ip;
+            activity.user_agent = user_agent;
+        }
+        refresh
+    }
+
+    fn expire_now(&self){
+        self.activity.lock().unwrap().expire = Utc::now();
+    }
+
+    pub fn info(&self) -> SessionInfo{
+        let activity = self.activity.lock().unwrap().clone();
+        SessionInfo{
+            id: self.id.clone(),
+            user: self.get_user().map(|user| user.name),
+            created: self.created.to_rfc3339(),
+            last_seen: activity.last_seen.to_rfc3339(),
+            expire: activity.expire.to_rfc3339(),
+            ip: activity.ip,
+            user_agent: activity.user_agent,
+        }
+    }
+
+    pub fn has_permission(&self, permission: &str) -> bool{
+        match &*self.user.lock().unwrap(){
+            Some(user) => user.has_permission(permission),
+            None => false,
+        }
+    }
+
+    pub fn has_role(&self, role: &str) -> bool{
+        match &*self.user.lock().unwrap(){
+            Some(user) => user.has_role(role),
+            None => false,
+        }
+    }
+
+    fn is_admin(&self) -> bool{
+        match &*self.user.lock().unwrap(){
+            Some(User{ level: UserLevel::Admin, .. }) | Some(User{ level: UserLevel::Root, .. }) => true,
+            _ => false,
+        }
+    }
+
+    fn user_name(&self) -> Option<String>{
+        self.get_user().map(|user| user.name)
     }
 
     fn set_user(&self, n_user: Option<User>){
@@ -57,32 +174,102 @@ impl Session{
         None
     }
 
+    pub fn get_data(&self, namespace: &str) -> Map<String, Value>{
+        match self.data.lock().unwrap().get(namespace){
+            Some(data) => data.clone(),
+            None => Map::new(),
+        }
+    }
+
+    // a null value removes the key, an emptied namespace is dropped
+    fn set_data(&self, namespace: &str, key: &str, value: Value){
+        let mut data = self.data.lock().unwrap();
+        if value.is_null(){
+            if let Some(values) = data.get_mut(namespace){
+                values.remove(key);
+                if values.is_empty(){
+                    data.remove(namespace);
+                }
+            }
+            return
+        }
+        data.entry(namespace.to_string()).or_insert_with(Map::new).insert(key.to_string(), value);
+    }
+
+    pub fn check_csrf_token(&self, token: &str) -> bool{
+        let expected = self.csrf_token.as_bytes();
+        let token = token.as_bytes();
+        if expected.len() != token.len(){
+            return false
+        }
+        // constant time comparison, the token must not leak through timing
+        expected.iter().zip(token.iter()).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+    }
+
+}
 
+fn gen_csrf_token() -> String{
+    format!("{:032x}", rand::thread_rng().gen::<u128>())
 }
 
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct SessionJson{
     id: String,
+    #[serde(default)]
+    csrf_token: Option<String>,
     user: Option<User>,
+    #[serde(default)]
+    data: HashMap<String, Map<String, Value>>,
+    #[serde(default)]
+    created: Option<String>,
+    #[serde(default)]
+    last_seen: Option<String>,
+    #[serde(default)]
+    ip: Option<String>,
+    #[serde(default)]
+    user_agent: Option<String>,
     expire:String,
 }
 
 impl From<&Arc<Session>> for SessionJson {
     fn from(sess: &Arc<Session>) -> Self {
         let user = &*sess.user.lock().unwrap();
+        let activity = sess.activity.lock().unwrap().clone();
         SessionJson { 
             id: sess.id.clone(), 
+            csrf_token: Some(sess.csrf_token.clone()),
             user: user.clone(), 
-            expire: sess.expire.to_rfc2822() }
+            data: sess.data.lock().unwrap().clone(),
+            created: Some(sess.created.to_rfc2822()),
+            last_seen: Some(activity.last_seen.to_rfc2822()),
+            ip: activity.ip,
+            user_agent: activity.user_agent,
+            expire: activity.expire.to_rfc2822() }
     }
 }
 
 impl Into<Arc<Session>> for SessionJson{
     fn into(self) -> Arc<Session> {
+        let expire: DateTime<Utc> = DateTime::parse_from_rfc2822(&self.expire).unwrap().into();
+        let parse = |date: Option<String>| -> Option<DateTime<Utc>> {
+            DateTime::parse_from_rfc2822(&date?).ok().map(|date| date.into())
+        };
+        let now = Utc::now();
+        let last_seen = parse(self.last_seen).unwrap_or(now);
         Arc::new(Session { 
             id: self.id, 
+            csrf_token: self.csrf_token.unwrap_or_else(gen_csrf_token),
             user: std::sync::Mutex::new(self.user), 
-            expire: DateTime::parse_from_rfc2822(&self.expire).unwrap().into() })
+            data: std::sync::Mutex::new(self.data),
+            created: parse(self.created).unwrap_or(now),
+            activity: std::sync::Mutex::new(Activity{
+                last_seen,
+                // sessions are saved when they refresh
+                last_refresh: last_seen,
+                expire,
+                ip: self.ip,
+                user_agent: self.user_agent,
+            }) })
     }
 }
 
@@ -94,8 +281,14 @@ pub enum AuthenticationType{
 }
 
 pub struct SessionManager{
-    path: String,
-    sessions: Mutex<Vec<Arc<Session>>>,
+    sessions: Arc<Mutex<HashMap<String, Arc<Session>>>>,
+    store: Arc<dyn SessionStore>,
+    changed: Arc<Mutex<HashSet<String>>>,
+    save_notify: Arc<Notify>,
+    expiry_interval: std::time::Duration,
+    save_delay: std::time::Duration,
+    lifetime: Duration,
+    roles: RoleManager,
     regex: Regex,
     a_type: AuthenticationType,
 
@@ -103,75 +296,247 @@ pub struct SessionManager{
 
 impl SessionManager{
 
-    pub fn new(quick_load_path: &str) -> SessionManager{
+    pub fn new(quick_load_path: &str, config: &SessionConfig, roles: RoleManager) -> SessionManager{
         SessionManager{
-            path: quick_load_path.to_string(),
-            sessions: Mutex::new(Vec::new()),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+            store: new_store(quick_load_path, config),
+            changed: Arc::new(Mutex::new(HashSet::new())),
+            save_notify: Arc::new(Notify::new()),
+            expiry_interval: std::time::Duration::from_secs(config.expiry_interval),
+            save_delay: std::time::Duration::from_secs(config.save_delay),
+            lifetime: Duration::seconds(config.lifetime as i64),
+            roles,
             regex: Regex::new("sessionID=([0-9a-f]+);").unwrap(),
             a_type: AuthenticationType::Unix,
         }
     }
 
     pub async fn load(&self) -> Result<(), UnicomError>{
-        let content = std::fs::read_to_string(&self.path)?;
-        let mut data_set: Vec<SessionJson> = serde_json::from_str(&content)?;
+        let data_set = self.store.load().await?;
         let mut sess = self.sessions.lock().await;
-        sess.push(Arc::new(Session::new()));
-        loop{
-            sess.push( match data_set.pop(){
-                Some(data) => data.into(),
-                None => break,
-            });
+        for data in data_set{
+            let session: Arc<Session> = data.into();
+            if session.has_expire(){
+                self.changed.lock().await.insert(session.id.clone());
+                continue
+            }
+            sess.insert(session.id.clone(), session);
         }
 
         Ok(())
     }
 
-    pub async fn save(&self){
-        let mut data_set: Vec<SessionJson> = Vec::new();
-        let sess = &mut *self.sessions.lock().await;
-        for session in sess.iter(){
-            data_set.push(session.into())
+    pub fn run(&self){
+        let sessions = self.sessions.clone();
+        let changed = self.changed.clone();
+        let save_notify = self.save_notify.clone();
+        let expiry_interval = self.expiry_interval;
+        tokio::spawn(async move{
+            let mut interval = tokio::time::interval(expiry_interval);
+            loop{
+                interval.tick().await;
+                let mut expired = Vec::new();
+                sessions.lock().await.retain(|id, session| {
+                    if session.has_expire(){
+                        expired.push(id.clone());
+                        return false
+                    }
+                    true
+                });
+                if !expired.is_empty(){
+                    changed.lock().await.extend(expired);
+                    save_notify.notify_one();
+                }
+            }
+        });
+
+        let sessions = self.sessions.clone();
+        let changed = self.changed.clone();
+        let save_notify = self.save_notify.clone();
+        let store = self.store.clone();
+        let save_delay = self.save_delay;
+        tokio::spawn(async move{
+            loop{
+                save_notify.notified().await;
+                // gather the other changes of the burst before writing
+                sleep(save_delay).await;
+                if let Err(e) = SessionManager::persist(&sessions, &changed, &store).await{
+                    LOGGER.error("session save error", e).await;
+                }
+            }
+        });
+    }
+
+    pub async fn flush(&self){
+        if let Err(e) = SessionManager::persist(&self.sessions, &self.changed, &self.store).await{
+            LOGGER.error("session save error", e).await;
         }
-        let data = serde_json::to_string(&data_set).unwrap();
-        println!("data : {} {}", data, &self.path);
-        let mut file = OpenOptions::new().write(true).create(true).open(&self.path).await.unwrap();
-        file.write_all(data.as_bytes()).await.unwrap();
-        file.sync_data().await.unwrap();
+    }
+
+    async fn persist(sessions: &Mutex<HashMap<String, Arc<Session>>>, changed: &Mutex<HashSet<String>>, 
+                        store: &Arc<dyn SessionStore>) -> Result<(), UnicomError>{
+        let changed = std::mem::take(&mut *changed.lock().await);
+        if changed.is_empty(){
+            return Ok(())
+        }
+        let snapshot = sessions.lock().await.clone();
+        store.persist(&snapshot, changed).await
+    }
 
+    async fn save(&self, id: &str){
+        self.changed.lock().await.insert(id.to_string());
+        self.save_notify.notify_one();
     }
 
     pub async fn create(&self) -> Arc<Session>{
-        let mut sess = self.sessions.lock().await;
-        sess.push(Arc::new(Session::new()));
-        let ret = sess.last().unwrap().clone();
-        drop(sess);
-        //println!("session save");
-        self.save().await;
-        ret
+        let session = Arc::new(Session::new(self.lifetime));
+        self.sessions.lock().await.insert(session.id.clone(), session.clone());
+        self.save(&session.id).await;
+        session
     }
 
-    async fn get(&self, id: &str) -> Option<Arc<Session>>{
-        let sess = &mut *self.sessions.lock().await;
-        let mut bad_index = Vec::new();
-        let mut ret = None;
+    // anonymous session neither stored nor sent as a cookie
+    pub fn transient(&self) -> Arc<Session>{
+        Arc::new(Session::new(self.lifetime))
+    }
+
+    // returns true when a new cookie should be sent with the slided expiration
+    pub async fn touch(&self, session: &Arc<Session>, ip: Option<String>, user_agent: Option<String>) -> bool{
+        let refresh = session.touch(self.lifetime, ip, user_agent);
+        // the roles config or the user groups may have changed since the login
+        let mut changed = false;
+        if let Some(user) = session.get_user(){
+            let resolved = match refresh{
+                true => unix_groups(&user.name).map(|groups| self.resolve_user(&user.name, groups)),
+                false => Some(self.resolve_user(&user.name, user.groups.clone())),
+            };
+            if resolved.as_ref() != Some(&user){
+                session.set_user(resolved);
+                changed = true;
+            }
+        }
+        if refresh || changed{
+            self.save(&session.id).await;
+        }
+        refresh
+    }
 
-        for (index, session) in sess.iter().enumerate(){
+    fn resolve_user(&self, user_name: &str, groups: Vec<String>) -> User{
+        let (roles, permissions) = self.roles.resolve(user_name, &groups);
+        let level = match permissions.iter().any(|permission| permission == "*"){
+            true => UserLevel::Admin,
+            false => UserLevel::Normal,
+        };
+        User{
+            name: user_name.to_string(),
+            level,
+            roles,
+            permissions,
+            groups,
+        }
+    }
+
+    pub async fn list(&self, caller_id: &str, user: Option<&str>) -> Result<Vec<SessionInfo>, UnicomError>{
+        let caller = self.caller(caller_id).await?;
+        if !caller.is_admin() && (user.is_none() || caller.user_name().as_deref() != user){
+            return Err(UnicomError::new(UnicomErrorKind::NotAllowed, "listing sessions of other users Not Allowed"))
+        }
+        let mut ret = Vec::new();
+        for session in self.sessions.lock().await.values(){
             if session.has_expire(){
-                bad_index.push(index);
                 continue
             }
-            if &session.id == id{
-                ret = Some(session.clone());
-                break;
+            if user.is_some() && session.user_name().as_deref() != user{
+                continue
             }
+            ret.push(session.info());
+        }
+        Ok(ret)
+    }
+
+    pub async fn revoke(&self, caller_id: &str, id: &str) -> Result<(), UnicomError>{
+        let caller = self.caller(caller_id).await?;
+        let session = match self.get(id).await{
+            Some(session) => session,
+            None => return Err(UnicomError::new(UnicomErrorKind::NotFound, &format!("session id not found {}", id))),
+        };
+        if !caller.is_admin() && (caller.user_name().is_none() || caller.user_name() != session.user_name()){
+            return Err(UnicomError::new(UnicomErrorKind::NotAllowed, "revoking session of other users Not Allowed"))
+        }
+        self.remove(&session).await;
+        Ok(())
+    }
+
+    pub async fn revoke_user(&self, caller_id: &str, user: &str) -> Result<usize, UnicomError>{
+        let caller = self.caller(caller_id).await?;
+        if !caller.is_admin() && caller.user_name().as_deref() != Some(user){
+            return Err(UnicomError::new(UnicomErrorKind::NotAllowed, "revoking session of other users Not Allowed"))
+        }
+        let sessions: Vec<Arc<Session>> = self.sessions.lock().await.values()
+            .filter(|session| session.user_name().as_deref() == Some(user))
+            .cloned()
+            .collect();
+        for session in &sessions{
+            self.remove(session).await;
+        }
+        Ok(sessions.len())
+    }
+
+    pub async fn logout(&self, id: &str) -> Result<(), UnicomError>{
+        let session = self.caller(id).await?;
+        self.remove(&session).await;
+        Ok(())
+    }
+
+    async fn caller(&self, id: &str) -> Result<Arc<Session>, UnicomError>{
+        match self.get(id).await{
+            Some(session) => Ok(session),
+            None => Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("session id not found {}", id))),
         }
+    }
+
+    async fn remove(&self, session: &Arc<Session>){
+        // requests still holding the session see it expired
+        session.expire_now();
+        self.sessions.lock().await.remove(&session.id);
+        self.save(&session.id).await;
+    }
 
-        for index in bad_index.iter().rev(){
-            sess.remove(*index);
+    async fn get(&self, id: &str) -> Option<Arc<Session>>{
+        match self.sessions.lock().await.get(id){
+            Some(session) if !session.has_expire() => Some(session.clone()),
+            _ => None,
         }
+    }
 
-        ret
+    pub async fn get_data(&self, id: &str, namespace: &str, key: Option<&str>) -> Result<Value, UnicomError>{
+        let session = match self.get(id).await{
+            Some(session) => session,
+            None => return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("session id not found {}", id))),
+        };
+        let data = session.get_data(namespace);
+        match key{
+            Some(key) => Ok(data.get(key).cloned().unwrap_or(Value::Null)),
+            None => Ok(Value::Object(data)),
+        }
+    }
+
+    pub async fn set_data(&self, id: &str, namespace: &str, key: &str, value: Value) -> Result<(), UnicomError>{
+        let session = match self.get(id).await{
+            Some(session) => session,
+            None => return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("session id not found {}", id))),
+        };
+        session.set_data(namespace, key, value);
+        self.save(&session.id).await;
+        Ok(())
+    }
+
+    pub async fn permissions(&self, id: &str) -> Result<Option<User>, UnicomError>{
+        Ok(self.caller(id).await?.get_user())
+    }
+
+    pub async fn has_permission(&self, id: &str, permission: &str) -> Result<bool, UnicomError>{
+        Ok(self.caller(id).await?.has_permission(permission))
     }
 
     pub async fn parse_session(&self, parts: &Request<Body>) -> Option<Arc<Session>>{
@@ -197,7 +562,7 @@ impl SessionManager{
 
         if user_name.len() == 0{
             session.set_user(None);
-            self.save().await;
+            self.save(&session.id).await;
             return Ok(())
         }
 
@@ -214,30 +579,30 @@ impl SessionManager{
                     return Err(UnicomError::new(UnicomErrorKind::NotAllowed, "User/*password Not Allowed"))
                 }
                 //println!("Ok");
-                let mut level = UserLevel::Normal;
-                let user = nix::unistd::User::from_name(user_name).unwrap().unwrap();
-                //println!("USER {:?}", user);
-                let name = CString::new(user.name).unwrap();
-                for gid in nix::unistd::getgrouplist(&name, user.gid).unwrap(){
-                    let group = nix::unistd::Group::from_gid(gid).unwrap().unwrap();
-                    if group.name == "sudo"{
-                        level = UserLevel::Admin
-                    }
-                    //println!("GROUP {:?}", group);
-                }
-
-                //println!("LEVEl {:?}", level);
-
-                session.set_user(Some(User{
-                    name: user_name.to_string(),
-                    level,
-                }));
+                let groups = match unix_groups(user_name){
+                    Some(groups) => groups,
+                    None => return Err(UnicomError::new(UnicomErrorKind::NotAllowed, "*User/password Not Allowed")),
+                };
+                session.set_user(Some(self.resolve_user(user_name, groups)));
 
-                self.save().await;
+                self.save(&session.id).await;
             },
         };
         Ok(())
     }
 
 
-}
\ No newline at end of file
+}
+
+// names of the unix groups of the user, none once the user is gone
+fn unix_groups(user_name: &str) -> Option<Vec<String>>{
+    let user = nix::unistd::User::from_name(user_name).ok()??;
+    let name = CString::new(user.name).ok()?;
+    let mut groups = Vec::new();
+    for gid in nix::unistd::getgrouplist(&name, user.gid).ok()?{
+        if let Ok(Some(group)) = nix::unistd::Group::from_gid(gid){
+            groups.push(group.name);
+        }
+    }
+    Some(groups)
+}
@@ -0,0 +1,100 @@
+use std::{collections::{HashMap, HashSet}, sync::Arc, path::Path};
+
+use async_trait::async_trait;
+use tokio::{fs::{self, OpenOptions}, io::AsyncWriteExt};
+use unicom_lib::error::UnicomError;
+
+use crate::config::{SessionBackend, SessionConfig};
+
+use super::session::{Session, SessionJson};
+
+#[async_trait]
+pub trait SessionStore: Send + Sync{
+    async fn load(&self) -> Result<Vec<SessionJson>, UnicomError>;
+
+    // `changed` holds the ids modified since the last call, an id missing from `sessions` was removed
+    async fn persist(&self, sessions: &HashMap<String, Arc<Session>>, changed: HashSet<String>) -> Result<(), UnicomError>;
+}
+
+pub fn new_store(default_path: &str, config: &SessionConfig) -> Arc<dyn SessionStore>{
+    let path = config.path.clone().unwrap_or(default_path.to_string());
+    match config.backend{
+        SessionBackend::Json => Arc::new(JsonStore{ path }),
+        #[cfg(feature = "sled-store")]
+        SessionBackend::Sled => Arc::new(SledStore::new(&path).expect("unable to open sled session store")),
+        #[cfg(not(feature = "sled-store"))]
+        SessionBackend::Sled => unreachable!("rejected by DaemonConfig::validate"),
+    }
+}
+
+pub struct JsonStore{
+    path: String,
+}
+
+#[async_trait]
+impl SessionStore for JsonStore{
+    async fn load(&self) -> Result<Vec<SessionJson>, UnicomError>{
+        if !Path::new(&self.path).exists(){
+            return Ok(Vec::new())
+        }
+        let content = fs::read_to_string(&self.path).await?;
+        Ok(serde_json::from_str(&content)?)
+    }
+
+    async fn persist(&self, sessions: &HashMap<String, Arc<Session>>, _changed: HashSet<String>) -> Result<(), UnicomError>{
+        let data_set: Vec<SessionJson> = sessions.values().map(|session| session.into()).collect();
+        let data = serde_json::to_string(&data_set)?;
+
+        // write next to the target then rename, a crash never leaves a half written file
+        let tmp_path = format!("{}.tmp", &self.path);
+        let mut file = OpenOptions::new().write(true).create(true).truncate(true).open(&tmp_path).await?;
+        file.write_all(data.as_bytes()).await?;
+        file.sync_data().await?;
+        drop(file);
+        fs::rename(&tmp_path, &self.path).await?;
+        Ok(())
+    }
+}
+
+#[cfg(feature = "sled-store")]
+pub struct SledStore{
+    db: sled::Db,
+}
+
+#[cfg(feature = "sled-store")]
+impl SledStore{
+    pub fn new(path: &str) -> Result<SledStore, UnicomError>{
+        Ok(SledStore{
+            db: sled::open(path).map_err(std::io::Error::from)?,
+        })
+    }
+}
+
+#[cfg(feature = "sled-store")]
+#[async_trait]
+impl SessionStore for SledStore{
+    async fn load(&self) -> Result<Vec<SessionJson>, UnicomError>{
+        let mut ret = Vec::new();
+        for entry in self.db.iter(){
+            let (_id, value) = entry.map_err(std::io::Error::from)?;
+            ret.push(serde_json::from_slice(&value)?);
+        }
+        Ok(ret)
+    }
+
+    async fn persist(&self, sessions: &HashMap<String, Arc<Session>>, changed: HashSet<String>) -> Result<(), UnicomError>{
+        for id in changed{
+            match sessions.get(&id){
+                Some(session) => {
+                    let data = serde_json::to_vec(&SessionJson::from(session))?;
+                    self.db.insert(id.as_bytes(), data).map_err(std::io::Error::from)?;
+                },
+                None => {
+                    self.db.remove(id.as_bytes()).map_err(std::io::Error::from)?;
+                },
+            }
+        }
+        self.db.flush_async().await.map_err(std::io::Error::from)?;
+        Ok(())
+    }
+}
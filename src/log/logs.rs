@@ -1,56 +1,289 @@
-use std::collections::HashMap;
-use super::LoggerMessage;
+use std::collections::{HashMap, VecDeque};
+
+use chrono::Utc;
+use serde_json::Map;
+
+use crate::config::LogConfig;
+
+use super::{LoggerMessage, LogRecord, LogLevel, LogFormat, store::LogStore, query::{LogQuery, LogSource}, tail::LogEvent, parse::{LogParser, LogParseConfig}};
+
+// an app ring is bounded by entries and by the bytes of its messages
+#[derive(Debug)]
+struct LogRing{
+    records: VecDeque<LogRecord>,
+    bytes: usize,
+    capacity: usize,
+    max_bytes: usize,
+}
+
+impl LogRing{
+    fn new(capacity: usize, max_bytes: usize) -> LogRing{
+        LogRing{
+            records: VecDeque::new(),
+            bytes: 0,
+            capacity,
+            max_bytes,
+        }
+    }
+
+    fn push(&mut self, record: LogRecord){
+        self.bytes += record.message.len();
+        self.records.push_back(record);
+        self.trim();
+    }
+
+    // the oldest records go first, the newest is always kept
+    fn trim(&mut self){
+        while self.records.len() > self.capacity || (self.bytes > self.max_bytes && self.records.len() > 1){
+            match self.records.pop_front(){
+                Some(record) => self.bytes -= record.message.len(),
+                None => break,
+            }
+        }
+    }
+}
 
 #[derive(Debug)]
 pub struct Logs{
-    logs : HashMap<String, Vec<String>>
+    logs : HashMap<String, LogRing>,
+    // unicom and http records
+    daemon_logs: HashMap<String, LogRing>,
+    // per app (capacity, max_bytes) overriding the defaults
+    limits: HashMap<String, (usize, usize)>,
+    parsers: HashMap<String, LogParser>,
+    default_parser: LogParser,
+    capacity: usize,
+    max_bytes: usize,
+    store: Option<LogStore>,
+    format: LogFormat,
+    // seq of the last record
+    seq: u64,
 }
 
 impl Logs{
-    pub fn new() -> Logs{
+    pub fn new(config: &LogConfig) -> Logs{
         Logs{
-            logs: HashMap::new()
+            logs: HashMap::new(),
+            daemon_logs: HashMap::new(),
+            limits: HashMap::new(),
+            parsers: HashMap::new(),
+            default_parser: LogParser::new(&LogParseConfig::default()).expect("invalid default log parser"),
+            capacity: config.memory_lines,
+            max_bytes: config.memory_bytes,
+            store: match config.enabled{
+                true => Some(LogStore::new(config)),
+                false => None,
+            },
+            format: config.format,
+            seq: Utc::now().timestamp_micros() as u64,
         }
     }
 
-    pub fn new_log(&mut self, log: LoggerMessage){
-        let value = match log{
+    fn next_seq(&mut self) -> u64{
+        self.seq += 1;
+        self.seq
+    }
+
+    // returns the record for the live tail
+    pub fn new_log(&mut self, log: LoggerMessage) -> LogEvent{
+        let (value, event) = match log{
             LoggerMessage::App { app, value, err, time } => {
-                let log = match err {
-                    true => format!("[{}]{}|[ERROR]{}", time.to_rfc3339(), app, value),
-                    false => format!("[{}]{}|{}", time.to_rfc3339(), app, value),
+                let (level, message, fields) = self.parsers.get(&app).unwrap_or(&self.default_parser).parse(&value, err);
+                let log = format!("[{}]{}|[{:?}]{}", time.to_rfc3339(), app, level, value);
+                let record = LogRecord{
+                    time: time.to_rfc3339(),
+                    stream: match err{
+                        true => "stderr".to_string(),
+                        false => "stdout".to_string(),
+                    },
+                    level,
+                    message,
+                    fields,
+                    seq: self.next_seq(),
                 };
-                self.add_app_log(&app, &log);
-                log
+                self.store(&LogStore::app_stream(&app), &record);
+                self.add_app_log(&app, record.clone());
+                (log, LogEvent{ source: "app".to_string(), app: Some(app), record })
             },
             LoggerMessage::Unicom { context, value, time } => {
-                format!("[{}]unicom|{} : {:?}", time.to_rfc3339(), context, value)
+                let record = LogRecord{
+                    time: time.to_rfc3339(),
+                    stream: "unicom".to_string(),
+                    level: LogLevel::Error,
+                    message: format!("{} : {:?}", context, value),
+                    fields: Map::new(),
+                    seq: self.next_seq(),
+                };
+                self.store("unicom", &record);
+                self.add_daemon_log("unicom", record.clone());
+                (format!("[{}]unicom|{}", record.time, record.message), LogEvent{ source: "unicom".to_string(), app: None, record })
+            },
+            LoggerMessage::Daemon { module, level, value, time } => {
+                let record = LogRecord{
+                    time: time.to_rfc3339(),
+                    stream: module,
+                    level,
+                    message: value,
+                    fields: Map::new(),
+                    seq: self.next_seq(),
+                };
+                self.store("unicom", &record);
+                self.add_daemon_log("unicom", record.clone());
+                (format!("[{}]{}|[{:?}]{}", record.time, record.stream, record.level, record.message), 
+                    LogEvent{ source: "unicom".to_string(), app: None, record })
             },
             LoggerMessage::Http { code, path, duration, time, method } => {
-                format!("[{}]http|[{}]{} {} {}", time.to_rfc3339(), code, method, path, duration)
+                let record = LogRecord{
+                    time: time.to_rfc3339(),
+                    stream: "http".to_string(),
+                    level: LogLevel::Info,
+                    message: format!("[{}]{} {} {}", code, method, path, duration),
+                    fields: Map::new(),
+                    seq: self.next_seq(),
+                };
+                self.store("http", &record);
+                self.add_daemon_log("http", record.clone());
+                (format!("[{}]http|{}", record.time, record.message), LogEvent{ source: "http".to_string(), app: None, record })
             },
         };
 
-        println!("{}", value);
+        match self.format{
+            LogFormat::Text => println!("{}", value),
+            LogFormat::Json => println!("{}", serde_json::to_string(&event).unwrap_or_default()),
+            // sd-daemon(3) priorities
+            LogFormat::Journald => {
+                let priority = match event.record.level{
+                    LogLevel::Error => 3,
+                    LogLevel::Warn => 4,
+                    LogLevel::Info => 6,
+                    LogLevel::Debug | LogLevel::Trace => 7,
+                };
+                println!("<{}>{}", priority, value);
+            },
+        }
+        event
+    }
 
+    fn store(&mut self, stream: &str, record: &LogRecord){
+        if let Some(store) = &mut self.store{
+            let line = serde_json::to_string(record).unwrap_or_default();
+            if let Err(e) = store.write(stream, &line){
+                eprintln!("log store {} error: {}", stream, e);
+            }
+        }
     }
 
-    pub fn get_log(&self, app: &str) -> Option<&Vec<String>>{
-        self.logs.get(app)
+    // the default parser is used without `parser`
+    pub fn configure_app(&mut self, app: &str, capacity: Option<usize>, max_bytes: Option<usize>, parser: Option<LogParser>){
+        match parser{
+            Some(parser) => self.parsers.insert(app.to_string(), parser),
+            None => self.parsers.remove(app),
+        };
+        let limits = (capacity.unwrap_or(self.capacity), max_bytes.unwrap_or(self.max_bytes));
+        self.limits.insert(app.to_string(), limits);
+        if let Some(ring) = self.logs.get_mut(app){
+            ring.capacity = limits.0;
+            ring.max_bytes = limits.1;
+            ring.trim();
+        }
     }
 
-    fn add_app_log(&mut self, app: &str, log: &str){
-        let logs = match self.logs.get_mut(app) {
-            Some(logs) => logs,
-            None => {
-                self.logs.insert(app.to_owned(), Vec::new());
-                self.logs.get_mut(app).unwrap()
-            },
+    // memory first, the stored log fills in the records older than the ring after a restart of the daemon
+    pub fn get_log(&self, app: &str) -> Option<Vec<LogRecord>>{
+        let capacity = self.limits.get(app).map(|limits| limits.0).unwrap_or(self.capacity);
+        let mut records: Vec<LogRecord> = match self.logs.get(app){
+            Some(ring) => ring.records.iter().cloned().collect(),
+            None => Vec::new(),
         };
-        logs.push(log.to_owned());
-        if logs.len() > 300{
-            logs.pop();
+        if let (true, Some(store)) = (records.len() < capacity, &self.store){
+            match store.read(&LogStore::app_stream(app), capacity){
+                Ok(lines) => {
+                    // the ring records are stored too
+                    let oldest = records.first().map(|record| record.seq);
+                    let mut stored: Vec<LogRecord> = lines.iter().map(|line| LogRecord::parse(line))
+                        .filter(|record| oldest.map(|oldest| record.seq < oldest).unwrap_or(true))
+                        .collect();
+                    stored.drain(..stored.len().saturating_sub(capacity - records.len()));
+                    stored.extend(records);
+                    records = stored;
+                },
+                Err(e) => eprintln!("log store {} read error: {}", app, e),
+            }
+        }
+        match records.len(){
+            0 => None,
+            _ => Some(records),
+        }
+    }
+
+    pub fn source(&self, query: &LogQuery) -> Result<LogSource, std::io::Error>{
+        if let Some(store) = &self.store{
+            return Ok(LogSource::Files(store.files(&query.stream())?))
+        }
+        let ring = match &query.app{
+            Some(app) if query.source == "app" => self.logs.get(app),
+            _ => self.daemon_logs.get(&query.source),
+        };
+        Ok(LogSource::Memory(ring.map(|ring| ring.records.iter().cloned().collect()).unwrap_or_default()))
+    }
+
+    fn add_daemon_log(&mut self, stream: &str, record: LogRecord){
+        if !self.daemon_logs.contains_key(stream){
+            self.daemon_logs.insert(stream.to_owned(), LogRing::new(self.capacity, self.max_bytes));
+        }
+        self.daemon_logs.get_mut(stream).unwrap().push(record);
+    }
+
+    fn add_app_log(&mut self, app: &str, record: LogRecord){
+        if !self.logs.contains_key(app){
+            let (capacity, max_bytes) = self.limits.get(app).cloned().unwrap_or((self.capacity, self.max_bytes));
+            self.logs.insert(app.to_owned(), LogRing::new(capacity, max_bytes));
         }
+        self.logs.get_mut(app).unwrap().push(record);
     }
 }
 
+#[cfg(test)]
+mod tests{
+    use serde_json::Map;
+
+    use super::{LogRing, LogRecord, LogLevel};
+
+    fn record(seq: u64, message: &str) -> LogRecord{
+        LogRecord{
+            time: "2024-01-01T00:00:00+00:00".to_string(),
+            stream: "stdout".to_string(),
+            level: LogLevel::Info,
+            message: message.to_string(),
+            fields: Map::new(),
+            seq,
+        }
+    }
+
+    fn seqs(ring: &LogRing) -> Vec<u64>{
+        ring.records.iter().map(|record| record.seq).collect()
+    }
+
+    #[test]
+    fn ring_keeps_the_newest_entries(){
+        let mut ring = LogRing::new(3, 1000);
+        for seq in 1..=5{
+            ring.push(record(seq, "line"));
+        }
+        assert_eq!(seqs(&ring), vec![3, 4, 5]);
+        assert_eq!(ring.bytes, 12);
+    }
+
+    #[test]
+    fn ring_evicts_the_oldest_over_max_bytes(){
+        let mut ring = LogRing::new(10, 10);
+        ring.push(record(1, "aaaa"));
+        ring.push(record(2, "bbbb"));
+        ring.push(record(3, "cccc"));
+        assert_eq!(seqs(&ring), vec![2, 3]);
+        // an entry larger than the limit is still kept alone
+        ring.push(record(4, "dddddddddddd"));
+        assert_eq!(seqs(&ring), vec![4]);
+        assert_eq!(ring.bytes, 12);
+    }
+}
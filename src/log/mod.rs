@@ -1,13 +1,63 @@
-use std::{time::Duration, sync::Arc};
+use std::{time::Duration, sync::{Arc, atomic::{AtomicU64, Ordering}}};
 
 use chrono::{Local, DateTime};
 use hyper::{StatusCode, Method};
-use tokio::sync::{mpsc::{Sender, self}, Mutex};
+use serde_json::{Map, Value};
+use tokio::{sync::{mpsc::{Sender, self}, Mutex, broadcast}, task};
 use unicom_lib::error::UnicomError;
 
-use self::logs::Logs;
+use crate::{DAEMON_CONFIG, config::LogConfig};
+
+use self::{logs::Logs, query::{LogQuery, LogPage}, tail::LogEvent};
 
 mod logs;
+mod store;
+pub mod query;
+pub mod tail;
+pub mod parse;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogLevel{
+    Trace,
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogRecord{
+    // rfc3339
+    pub time: String,
+    // stdout or stderr for the apps, the module for the daemon messages, unicom or http
+    pub stream: String,
+    pub level: LogLevel,
+    pub message: String,
+    // parsed from json or regex app log lines
+    #[serde(default, skip_serializing_if = "Map::is_empty")]
+    pub fields: Map<String, Value>,
+    // increases with every record, seeded from the clock at startup so it keeps increasing across restarts
+    #[serde(default)]
+    pub seq: u64,
+}
+
+impl LogRecord{
+    // a stored line, lines written before the records were json are kept as their message
+    pub fn parse(line: &str) -> LogRecord{
+        match serde_json::from_str(line){
+            Ok(record) => record,
+            Err(_) => LogRecord{
+                time: String::new(),
+                stream: String::new(),
+                level: LogLevel::Info,
+                message: line.to_string(),
+                fields: Map::new(),
+                seq: 0,
+            },
+        }
+    }
+}
 
 #[derive(Debug)]
 pub enum LoggerMessage{
@@ -24,6 +74,13 @@ pub enum LoggerMessage{
         time: DateTime<Local>,
     },
 
+    Daemon {
+        module: String,
+        level: LogLevel,
+        value: String,
+        time: DateTime<Local>,
+    },
+
     Http {
         code: String,
         method: String,
@@ -33,62 +90,227 @@ pub enum LoggerMessage{
     }
 }
 
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat{
+    Text,
+    // one json record per line
+    Json,
+    // text with the <priority> prefix parsed by journald
+    Journald,
+}
+
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogOverflow{
+    // the app output reader waits, the app blocks on its full pipe
+    Wait,
+    Drop,
+}
+
+#[derive(Debug, Default)]
+struct LoggerCounters{
+    sent: AtomicU64,
+    dropped_app: AtomicU64,
+    dropped_unicom: AtomicU64,
+    dropped_http: AtomicU64,
+}
+
+impl LoggerCounters{
+    fn dropped(&self) -> u64{
+        self.dropped_app.load(Ordering::Relaxed) + self.dropped_unicom.load(Ordering::Relaxed) + self.dropped_http.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct LoggerMetrics{
+    queue_capacity: usize,
+    queued: usize,
+    sent: u64,
+    dropped_app: u64,
+    dropped_unicom: u64,
+    dropped_http: u64,
+}
+
 #[derive(Debug)]
 pub struct Logger{
     tx: Sender<LoggerMessage>,
+    config: LogConfig,
     pub logs: Arc<Mutex<Logs>>,
+    // live tail subscribers
+    events: broadcast::Sender<LogEvent>,
+    counters: Arc<LoggerCounters>,
 }
 
 impl Logger{
     pub fn new() -> Logger{
-        let (tx, mut rx) = mpsc::channel(64);
-        let logs = Arc::new(Mutex::new(Logs::new()));
-        let ret = Logger { tx, logs: logs.clone()  };
-        tokio::spawn(async move{
-            loop{
-                let log = rx.recv().await;
-                if log.is_none(){
-                    break
+        let config = DAEMON_CONFIG.logs.clone();
+        let (tx, mut rx) = mpsc::channel(config.queue.max(1));
+        let logs = Arc::new(Mutex::new(Logs::new(&config)));
+        let (events, _) = broadcast::channel(1024);
+        let counters = Arc::new(LoggerCounters::default());
+        let ret = Logger { tx, config, logs: logs.clone(), events: events.clone(), counters: counters.clone() };
+        // the store writes and rotations are blocking file io, kept off the async workers
+        task::spawn_blocking(move ||{
+            let mut reported = 0;
+            while let Some(log) = rx.blocking_recv(){
+                let mut logs = logs.blocking_lock();
+                // the drops are told once the logger caught up
+                let dropped = counters.dropped();
+                if dropped > reported{
+                    let event = logs.new_log(LoggerMessage::Daemon { 
+                        module: "log".to_string(), 
+                        level: LogLevel::Warn, 
+                        value: format!("{} log messages dropped, the logger queue was full", dropped - reported), 
+                        time: Local::now() 
+                    });
+                    reported = dropped;
+                    // fails only without subscriber
+                    events.send(event).unwrap_or_default();
                 }
-                logs.lock().await.new_log(log.unwrap());
+                let event = logs.new_log(log);
+                drop(logs);
+                events.send(event).unwrap_or_default();
             }
-
         });
         ret
     }
 
+    pub fn subscribe(&self) -> broadcast::Receiver<LogEvent>{
+        self.events.subscribe()
+    }
+
+    pub fn metrics(&self) -> LoggerMetrics{
+        LoggerMetrics{
+            queue_capacity: self.config.queue.max(1),
+            queued: self.config.queue.max(1) - self.tx.capacity(),
+            sent: self.counters.sent.load(Ordering::Relaxed),
+            dropped_app: self.counters.dropped_app.load(Ordering::Relaxed),
+            dropped_unicom: self.counters.dropped_unicom.load(Ordering::Relaxed),
+            dropped_http: self.counters.dropped_http.load(Ordering::Relaxed),
+        }
+    }
+
+    // memory and stored records of an app, read on a blocking thread
+    pub async fn get_log(&self, app: &str) -> Result<Option<Vec<LogRecord>>, UnicomError>{
+        let logs = self.logs.clone();
+        let app = app.to_string();
+        Ok(task::spawn_blocking(move || logs.blocking_lock().get_log(&app)).await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?)
+    }
+
+    // the store is read outside of the logs lock
+    pub async fn query(&self, query: LogQuery) -> Result<LogPage, UnicomError>{
+        let source = self.logs.lock().await.source(&query)?;
+        Ok(task::spawn_blocking(move || query.run(source)).await
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))??)
+    }
+
+    // the level of the module in [logs.modules], [logs] level otherwise
+    pub fn enabled(&self, module: &str, level: LogLevel) -> bool{
+        level >= *self.config.modules.get(module).unwrap_or(&self.config.level)
+    }
+
+    fn dropped_counter(&self, message: &LoggerMessage) -> &AtomicU64{
+        match message{
+            LoggerMessage::App { .. } => &self.counters.dropped_app,
+            LoggerMessage::Http { .. } => &self.counters.dropped_http,
+            LoggerMessage::Unicom { .. } | LoggerMessage::Daemon { .. } => &self.counters.dropped_unicom,
+        }
+    }
+
+    // never waits, a full or closed queue drops the message and counts it
+    fn send(&self, message: LoggerMessage){
+        let dropped = self.dropped_counter(&message);
+        match self.tx.try_send(message){
+            Ok(()) => self.counters.sent.fetch_add(1, Ordering::Relaxed),
+            Err(_) => dropped.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    // app output follows [logs] app_overflow
+    async fn send_app(&self, message: LoggerMessage){
+        if self.config.app_overflow == LogOverflow::Drop{
+            return self.send(message)
+        }
+        let dropped = self.dropped_counter(&message);
+        match self.tx.send(message).await{
+            Ok(()) => self.counters.sent.fetch_add(1, Ordering::Relaxed),
+            Err(_) => dropped.fetch_add(1, Ordering::Relaxed),
+        };
+    }
+
+    pub async fn log(&self, level: LogLevel, module: &str, value: String){
+        self.log_now(level, module, value)
+    }
+
+    // same as log for synchronous code
+    pub fn log_now(&self, level: LogLevel, module: &str, value: String){
+        if !self.enabled(module, level){
+            return
+        }
+        self.send(LoggerMessage::Daemon { 
+            module: module.to_owned(), 
+            level, 
+            value, 
+            time: Local::now() 
+        });
+    }
+
+    pub async fn trace(&self, module: &str, value: String){
+        self.log(LogLevel::Trace, module, value).await
+    }
+
+    pub async fn debug(&self, module: &str, value: String){
+        self.log(LogLevel::Debug, module, value).await
+    }
+
+    pub async fn info(&self, module: &str, value: String){
+        self.log(LogLevel::Info, module, value).await
+    }
+
+    pub async fn warn(&self, module: &str, value: String){
+        self.log(LogLevel::Warn, module, value).await
+    }
+
     pub async fn app_stdout(&self, name: &str, value: String){
-        self.tx.send(LoggerMessage::App { 
+        self.send_app(LoggerMessage::App { 
             app: name.to_owned(), 
             value, err: false, 
             time: Local::now() 
-        }).await.expect("log tx send error");
+        }).await
     }
 
     pub async fn app_stderr(&self, name: &str, value: String){
-        self.tx.send(LoggerMessage::App { 
+        self.send_app(LoggerMessage::App { 
             app: name.to_owned(), 
             value, err: true, 
             time: Local::now() 
-        }).await.expect("log tx send error");
+        }).await
     }
 
     pub async fn error(&self, context: &str, value: UnicomError){
-        self.tx.send(LoggerMessage::Unicom { 
+        if !self.enabled("unicom", LogLevel::Error){
+            return
+        }
+        self.send(LoggerMessage::Unicom { 
             context: context.to_owned(), 
             value, 
             time: Local::now() 
-        }).await.expect("log tx send error");
+        });
     }
 
     pub async fn http(&self, path: &str, code: StatusCode, method: &Method, duration: Duration){
-        self.tx.send(LoggerMessage::Http { 
+        if !self.enabled("http", LogLevel::Info){
+            return
+        }
+        self.send(LoggerMessage::Http { 
             code: code.to_string(), 
             method: method.to_string(),
             path: path.to_owned(), 
             duration: duration.as_secs_f32(), 
             time: Local::now()
-        }).await.expect("log tx send error");
+        });
     }
 
-}
\ No newline at end of file
+}
@@ -0,0 +1,196 @@
+use regex::Regex;
+use serde_json::{Map, Value};
+use unicom_lib::error::UnicomError;
+
+use super::LogLevel;
+
+// "INFO:root:message" from python logging, "[warn] message", "ERROR: message", "DEBUG - message",
+// a bare level is only recognized in upper case so "Error reading ..." stays a message
+const PREFIX_REGEX: &str = r"^(?P<level>\[(?i:trace|debug|info|notice|warn|warning|error|err|critical|crit|fatal)\]|TRACE|DEBUG|INFO|NOTICE|WARN|WARNING|ERROR|ERR|CRITICAL|CRIT|FATAL)(?::(?P<logger>[^:\s]*):|\s*[:|-]?\s+)(?P<message>.*)$";
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct LogParseConfig{
+    // lines holding a json object, level and message are taken from its keys, the rest becomes fields
+    #[serde(default = "default_true")]
+    pub json: bool,
+    // level prefixes of the common logging libraries
+    #[serde(default = "default_true")]
+    pub prefixes: bool,
+    // regexes tried first, named groups `level` and `message`, the other groups become fields
+    #[serde(default)]
+    pub rules: Vec<String>,
+    // levels of the lines matching nothing
+    #[serde(default = "default_stdout_level")]
+    pub stdout_level: LogLevel,
+    #[serde(default = "default_stderr_level")]
+    pub stderr_level: LogLevel,
+}
+
+impl Default for LogParseConfig{
+    fn default() -> Self {
+        LogParseConfig{
+            json: true,
+            prefixes: true,
+            rules: Vec::new(),
+            stdout_level: default_stdout_level(),
+            stderr_level: default_stderr_level(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct LogParser{
+    config: LogParseConfig,
+    rules: Vec<Regex>,
+    prefix: Regex,
+}
+
+impl LogParser{
+    pub fn new(config: &LogParseConfig) -> Result<LogParser, UnicomError>{
+        let mut rules = Vec::new();
+        for rule in &config.rules{
+            rules.push(Regex::new(rule)?);
+        }
+        Ok(LogParser{
+            config: config.clone(),
+            rules,
+            prefix: Regex::new(PREFIX_REGEX)?,
+        })
+    }
+
+    // level, message and fields of an app output line
+    pub fn parse(&self, line: &str, err: bool) -> (LogLevel, String, Map<String, Value>){
+        let default_level = match err{
+            true => self.config.stderr_level,
+            false => self.config.stdout_level,
+        };
+
+        if self.config.json && line.trim_start().starts_with('{'){
+            if let Ok(Value::Object(mut fields)) = serde_json::from_str::<Value>(line){
+                let level = ["level", "levelname", "severity", "lvl"].iter()
+                    .find_map(|key| fields.remove(*key))
+                    .and_then(|level| level.as_str().and_then(parse_level))
+                    .unwrap_or(default_level);
+                let message = ["message", "msg"].iter()
+                    .find_map(|key| fields.remove(*key))
+                    .map(|message| match message{
+                        Value::String(message) => message,
+                        message => message.to_string(),
+                    })
+                    .unwrap_or_else(|| line.to_string());
+                return (level, message, fields)
+            }
+        }
+
+        for rule in &self.rules{
+            if let Some(parsed) = self.captures(rule, line, default_level){
+                return parsed
+            }
+        }
+        if self.config.prefixes{
+            if let Some(parsed) = self.captures(&self.prefix, line, default_level){
+                return parsed
+            }
+        }
+        (default_level, line.to_string(), Map::new())
+    }
+
+    fn captures(&self, regex: &Regex, line: &str, default_level: LogLevel) -> Option<(LogLevel, String, Map<String, Value>)>{
+        let captures = regex.captures(line)?;
+        let mut level = default_level;
+        let mut message = line.to_string();
+        let mut fields = Map::new();
+        for name in regex.capture_names().flatten(){
+            let value = match captures.name(name){
+                Some(value) => value.as_str(),
+                None => continue,
+            };
+            match name{
+                "level" => level = parse_level(value).unwrap_or(default_level),
+                "message" => message = value.to_string(),
+                name if value.len() > 0 => {
+                    fields.insert(name.to_string(), Value::String(value.to_string()));
+                },
+                _ => (),
+            }
+        }
+        Some((level, message, fields))
+    }
+}
+
+fn parse_level(level: &str) -> Option<LogLevel>{
+    match level.trim_matches(|c| c == '[' || c == ']').to_lowercase().as_str(){
+        "trace" => Some(LogLevel::Trace),
+        "debug" => Some(LogLevel::Debug),
+        "info" | "notice" => Some(LogLevel::Info),
+        "warn" | "warning" => Some(LogLevel::Warn),
+        "error" | "err" | "critical" | "crit" | "fatal" | "alert" | "emerg" => Some(LogLevel::Error),
+        _ => None,
+    }
+}
+
+fn default_true() -> bool{
+    true
+}
+
+fn default_stdout_level() -> LogLevel{
+    LogLevel::Info
+}
+
+fn default_stderr_level() -> LogLevel{
+    LogLevel::Error
+}
+
+#[cfg(test)]
+mod tests{
+    use serde_json::Value;
+
+    use super::{LogParser, LogParseConfig, LogLevel};
+
+    fn parser(rules: &[&str]) -> LogParser{
+        let mut config = LogParseConfig::default();
+        config.rules = rules.iter().map(|rule| rule.to_string()).collect();
+        LogParser::new(&config).unwrap()
+    }
+
+    #[test]
+    fn json_keys_give_level_message_and_fields(){
+        let (level, message, fields) = parser(&[]).parse(r#"{"level":"warning","msg":"disk low","free":12}"#, false);
+        assert_eq!(level, LogLevel::Warn);
+        assert_eq!(message, "disk low");
+        assert_eq!(fields.get("free"), Some(&Value::from(12)));
+        assert!(fields.get("level").is_none());
+    }
+
+    #[test]
+    fn json_without_message_keeps_the_line(){
+        let line = r#"{"level":"info","event":"started"}"#;
+        let (level, message, _) = parser(&[]).parse(line, true);
+        assert_eq!(level, LogLevel::Info);
+        assert_eq!(message, line);
+    }
+
+    #[test]
+    fn prefixes_set_the_level(){
+        let parser = parser(&[]);
+        let (level, message, fields) = parser.parse("INFO:root:ready", true);
+        assert_eq!((level, message.as_str()), (LogLevel::Info, "ready"));
+        assert_eq!(fields.get("logger"), Some(&Value::from("root")));
+        assert_eq!(parser.parse("[warn] slow request", false).0, LogLevel::Warn);
+        assert_eq!(parser.parse("ERROR: boom", false).1, "boom");
+    }
+
+    #[test]
+    fn unmatched_lines_take_the_stream_level(){
+        let parser = parser(&[]);
+        assert_eq!(parser.parse("Error reading file", false), (LogLevel::Info, "Error reading file".to_string(), Default::default()));
+        assert_eq!(parser.parse("plain", true).0, LogLevel::Error);
+    }
+
+    #[test]
+    fn rules_come_before_prefixes(){
+        let (level, message, fields) = parser(&[r"^(?P<level>\w+) \[(?P<module>\w+)\] (?P<message>.*)$"]).parse("DEBUG [db] connected", false);
+        assert_eq!((level, message.as_str()), (LogLevel::Debug, "connected"));
+        assert_eq!(fields.get("module"), Some(&Value::from("db")));
+    }
+}
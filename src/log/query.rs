@@ -0,0 +1,274 @@
+use std::{io, path::PathBuf};
+
+use chrono::{DateTime, FixedOffset};
+use regex::Regex;
+use serde_json::{Map, Value};
+use unicom_lib::error::{UnicomError, UnicomErrorKind};
+
+use super::{LogRecord, LogLevel, store::{LogStore, read_lines}};
+
+const DEFAULT_LIMIT: usize = 100;
+const MAX_LIMIT: usize = 1000;
+
+// where the records of a query are read, the store when enabled
+pub enum LogSource{
+    // oldest first
+    Files(Vec<PathBuf>),
+    Memory(Vec<LogRecord>),
+}
+
+pub struct LogQuery{
+    // app, unicom or http
+    pub source: String,
+    pub app: Option<String>,
+    matcher: LogMatcher,
+    since: Option<DateTime<FixedOffset>>,
+    until: Option<DateTime<FixedOffset>>,
+    limit: usize,
+    // seq of the oldest record of the previous page, only older records are returned
+    cursor: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct LogPage{
+    // oldest first
+    pub records: Vec<LogRecord>,
+    // none once the oldest matching record is returned
+    next_cursor: Option<u64>,
+}
+
+// the level, search and regex filters of the queries and of the live tail
+pub struct LogMatcher{
+    // minimum level
+    level: Option<LogLevel>,
+    search: Option<String>,
+    regex: Option<Regex>,
+}
+
+impl LogMatcher{
+    pub fn from_parameters(parameters: &Map<String, Value>) -> Result<LogMatcher, UnicomError>{
+        let get = |name: &str| parameters.get(name).and_then(|value| value.as_str()).filter(|value| !value.is_empty());
+        Ok(LogMatcher{
+            level: match get("level"){
+                Some(level) => Some(serde_json::from_value(Value::String(level.to_lowercase()))?),
+                None => None,
+            },
+            search: get("search").map(|search| search.to_lowercase()),
+            regex: match get("regex"){
+                Some(regex) => Some(Regex::new(regex)?),
+                None => None,
+            },
+        })
+    }
+
+    pub fn matches(&self, record: &LogRecord) -> bool{
+        if let Some(level) = self.level{
+            if record.level < level{
+                return false
+            }
+        }
+        if let Some(search) = &self.search{
+            if !record.message.to_lowercase().contains(search){
+                return false
+            }
+        }
+        if let Some(regex) = &self.regex{
+            if !regex.is_match(&record.message){
+                return false
+            }
+        }
+        true
+    }
+}
+
+impl LogQuery{
+    pub fn from_parameters(parameters: &Map<String, Value>) -> Result<LogQuery, UnicomError>{
+        let get = |name: &str| parameters.get(name).and_then(|value| value.as_str()).filter(|value| !value.is_empty());
+        let source = get("source").unwrap_or("app").to_string();
+        let app = get("app").map(|app| app.to_string());
+        match (source.as_str(), &app){
+            ("app", None) => return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, "app source needs an app")),
+            ("app", Some(_)) | ("unicom", _) | ("http", _) => (),
+            (source, _) => return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("unknown log source {}", source))),
+        }
+        let number = |name: &str| -> Result<Option<u64>, UnicomError>{
+            match get(name){
+                Some(value) => value.parse().map(Some).map_err(|_| UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("{} is not a number", name))),
+                None => Ok(None),
+            }
+        };
+
+        Ok(LogQuery{
+            source,
+            app,
+            matcher: LogMatcher::from_parameters(parameters)?,
+            since: parse_time(get("since"))?,
+            until: parse_time(get("until"))?,
+            limit: (number("limit")?.unwrap_or(DEFAULT_LIMIT as u64) as usize).min(MAX_LIMIT),
+            cursor: number("cursor")?,
+        })
+    }
+
+    // stream of the log store
+    pub fn stream(&self) -> String{
+        match &self.app{
+            Some(app) if self.source == "app" => LogStore::app_stream(app),
+            _ => self.source.clone(),
+        }
+    }
+
+    fn matches(&self, record: &LogRecord, time: Option<&DateTime<FixedOffset>>) -> bool{
+        if let (Some(until), Some(time)) = (&self.until, time){
+            if time > until{
+                return false
+            }
+        }
+        self.matcher.matches(record)
+    }
+
+    // walks the records from the newest, stops at `since`
+    pub fn run(&self, source: LogSource) -> io::Result<LogPage>{
+        let mut records = Vec::new();
+        let mut page = |record: LogRecord| -> bool{
+            // already returned by a previous page
+            if let Some(cursor) = self.cursor{
+                if record.seq >= cursor{
+                    return true
+                }
+            }
+            let time = DateTime::parse_from_rfc3339(&record.time).ok();
+            if let (Some(since), Some(time)) = (&self.since, &time){
+                if time < since{
+                    return false
+                }
+            }
+            if !self.matches(&record, time.as_ref()){
+                return true
+            }
+            records.push(record);
+            records.len() <= self.limit
+        };
+
+        let mut complete = true;
+        match source{
+            LogSource::Memory(memory) => {
+                for record in memory.into_iter().rev(){
+                    if !page(record){
+                        complete = false;
+                        break
+                    }
+                }
+            },
+            LogSource::Files(files) => {
+                'files: for file in files.iter().rev(){
+                    for line in read_lines(file)?.iter().rev(){
+                        if !page(LogRecord::parse(line)){
+                            complete = false;
+                            break 'files
+                        }
+                    }
+                }
+            },
+        }
+
+        // one record past the limit tells that an older page exists
+        let more = !complete && records.len() > self.limit;
+        records.truncate(self.limit);
+        records.reverse();
+        // records stored before the seq was added cannot be paged
+        let next_cursor = match records.first(){
+            Some(oldest) if more && oldest.seq > 0 => Some(oldest.seq),
+            _ => None,
+        };
+        Ok(LogPage{
+            records,
+            next_cursor,
+        })
+    }
+}
+
+fn parse_time(value: Option<&str>) -> Result<Option<DateTime<FixedOffset>>, UnicomError>{
+    match value{
+        Some(value) => DateTime::parse_from_rfc3339(value).map(Some)
+            .map_err(|_| UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("{} is not a rfc3339 time", value))),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests{
+    use serde_json::{json, Map, Value};
+
+    use super::{LogQuery, LogSource, LogRecord, LogLevel};
+
+    fn record(seq: u64, level: LogLevel, message: &str) -> LogRecord{
+        LogRecord{
+            time: "2024-01-01T00:00:00+00:00".to_string(),
+            stream: "stdout".to_string(),
+            level,
+            message: message.to_string(),
+            fields: Map::new(),
+            seq,
+        }
+    }
+
+    fn records(count: u64) -> Vec<LogRecord>{
+        (1..=count).map(|seq| record(seq, LogLevel::Info, &format!("line {}", seq))).collect()
+    }
+
+    fn query(parameters: Value) -> LogQuery{
+        LogQuery::from_parameters(parameters.as_object().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn pages_walk_back_from_the_newest(){
+        let first = query(json!({"app": "test", "limit": "100"})).run(LogSource::Memory(records(250))).unwrap();
+        assert_eq!(first.records.len(), 100);
+        assert_eq!(first.records.first().unwrap().seq, 151);
+        assert_eq!(first.records.last().unwrap().seq, 250);
+        assert_eq!(first.next_cursor, Some(151));
+
+        let second = query(json!({"app": "test", "limit": "100", "cursor": "151"})).run(LogSource::Memory(records(250))).unwrap();
+        assert_eq!(second.records.first().unwrap().seq, 51);
+        assert_eq!(second.records.last().unwrap().seq, 150);
+        assert_eq!(second.next_cursor, Some(51));
+
+        let last = query(json!({"app": "test", "limit": "100", "cursor": "51"})).run(LogSource::Memory(records(250))).unwrap();
+        assert_eq!(last.records.len(), 50);
+        assert_eq!(last.records.first().unwrap().seq, 1);
+        assert_eq!(last.next_cursor, None);
+    }
+
+    #[test]
+    fn new_records_do_not_shift_the_next_page(){
+        let first = query(json!({"app": "test", "limit": "10"})).run(LogSource::Memory(records(30))).unwrap();
+        let cursor = first.next_cursor.unwrap().to_string();
+        // records logged between the two pages
+        let second = query(json!({"app": "test", "limit": "10", "cursor": cursor})).run(LogSource::Memory(records(45))).unwrap();
+        assert_eq!(second.records.first().unwrap().seq, 11);
+        assert_eq!(second.records.last().unwrap().seq, 20);
+    }
+
+    #[test]
+    fn pages_hold_only_matching_records(){
+        let mut memory = records(20);
+        memory.push(record(21, LogLevel::Error, "disk full"));
+        memory.extend((22..=30).map(|seq| record(seq, LogLevel::Info, "ok")));
+        memory.push(record(31, LogLevel::Error, "disk full again"));
+
+        let page = query(json!({"app": "test", "level": "error", "limit": "1"})).run(LogSource::Memory(memory.clone())).unwrap();
+        assert_eq!(page.records.len(), 1);
+        assert_eq!(page.records[0].seq, 31);
+        assert_eq!(page.next_cursor, Some(31));
+
+        let page = query(json!({"app": "test", "search": "DISK", "limit": "1", "cursor": "31"})).run(LogSource::Memory(memory)).unwrap();
+        assert_eq!(page.records[0].seq, 21);
+        assert_eq!(page.next_cursor, None);
+    }
+
+    #[test]
+    fn limit_is_capped(){
+        let page = query(json!({"app": "test", "limit": "5000"})).run(LogSource::Memory(records(1500))).unwrap();
+        assert_eq!(page.records.len(), super::MAX_LIMIT);
+    }
+}
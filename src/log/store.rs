@@ -0,0 +1,279 @@
+use std::{collections::HashMap, fs::{self, File, OpenOptions}, io::{self, Write, BufRead, BufReader}, path::{Path, PathBuf}, time::{SystemTime, Duration}};
+
+use chrono::Local;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+use crate::config::LogConfig;
+
+#[derive(Debug)]
+struct LogFile{
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+// one file per app under apps/, unicom.log and http.log, rotated to <file>.<timestamp>[.gz]
+#[derive(Debug)]
+pub struct LogStore{
+    dir: PathBuf,
+    config: LogConfig,
+    files: HashMap<String, LogFile>,
+}
+
+impl LogStore{
+    pub fn new(config: &LogConfig) -> LogStore{
+        LogStore{
+            dir: PathBuf::from(&config.dir),
+            config: config.clone(),
+            files: HashMap::new(),
+        }
+    }
+
+    pub fn app_stream(name: &str) -> String{
+        format!("apps/{}", name.replace('/', "_"))
+    }
+
+    fn path(&self, stream: &str) -> PathBuf{
+        self.dir.join(format!("{}.log", stream))
+    }
+
+    pub fn write(&mut self, stream: &str, line: &str) -> io::Result<()>{
+        if self.must_rotate(stream, line.len() as u64 + 1){
+            self.rotate(stream)?;
+        }
+        if !self.files.contains_key(stream){
+            let file = self.open(stream)?;
+            self.files.insert(stream.to_string(), file);
+        }
+        let log_file = self.files.get_mut(stream).unwrap();
+        log_file.file.write_all(format!("{}\n", line).as_bytes())?;
+        log_file.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn open(&self, stream: &str) -> io::Result<LogFile>{
+        let path = self.path(stream);
+        if let Some(parent) = path.parent(){
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let metadata = file.metadata()?;
+        Ok(LogFile{
+            file,
+            size: metadata.len(),
+            opened: metadata.created().unwrap_or(SystemTime::now()),
+        })
+    }
+
+    fn must_rotate(&self, stream: &str, len: u64) -> bool{
+        let log_file = match self.files.get(stream){
+            Some(log_file) => log_file,
+            None => return false,
+        };
+        if log_file.size > 0 && log_file.size + len > self.config.max_size{
+            return true
+        }
+        match self.config.rotate_interval{
+            Some(interval) => log_file.opened.elapsed().unwrap_or_default() > Duration::from_secs(interval),
+            None => false,
+        }
+    }
+
+    fn rotate(&mut self, stream: &str) -> io::Result<()>{
+        self.files.remove(stream);
+        let path = self.path(stream);
+        let rotated = rotated_path(&path);
+        fs::rename(&path, &rotated)?;
+
+        let compress = self.config.compress;
+        let retention = self.config.retention;
+        let max_age = self.config.max_age.map(|days| Duration::from_secs(days * 24 * 3600));
+        // compression and pruning do not hold the logger
+        tokio::task::spawn_blocking(move || {
+            if compress{
+                if let Err(e) = gzip(&rotated){
+                    eprintln!("log compress {} error: {}", rotated.display(), e);
+                }
+            }
+            if let Err(e) = prune(&path, retention, max_age){
+                eprintln!("log prune {} error: {}", path.display(), e);
+            }
+        });
+        Ok(())
+    }
+
+    // rotated files then the current one, oldest first
+    pub fn files(&self, stream: &str) -> io::Result<Vec<PathBuf>>{
+        let path = self.path(stream);
+        let mut files = rotated_files(&path)?;
+        if path.exists(){
+            files.push(path);
+        }
+        Ok(files)
+    }
+
+    // the last `limit` lines of the stream, older lines are searched in the rotated files
+    pub fn read(&self, stream: &str, limit: usize) -> io::Result<Vec<String>>{
+        let files = self.files(stream)?;
+        let mut ret: Vec<String> = Vec::new();
+        for file in files.iter().rev(){
+            let mut lines = read_lines(file)?;
+            if lines.len() + ret.len() > limit{
+                lines.drain(..lines.len() + ret.len() - limit);
+            }
+            lines.extend(ret);
+            ret = lines;
+            if ret.len() >= limit{
+                break
+            }
+        }
+        Ok(ret)
+    }
+}
+
+pub fn read_lines(path: &Path) -> io::Result<Vec<String>>{
+    let file = File::open(path)?;
+    let reader: Box<dyn BufRead> = match path.extension().map(|extension| extension == "gz").unwrap_or(false){
+        true => Box::new(BufReader::new(GzDecoder::new(file))),
+        false => Box::new(BufReader::new(file)),
+    };
+    reader.lines().collect()
+}
+
+// <file>.<timestamp>, a -<n> counter is added when the second already has a rotated file
+fn rotated_path(path: &Path) -> PathBuf{
+    let timestamp = Local::now().format("%Y%m%d%H%M%S");
+    let mut counter = 0;
+    loop{
+        let rotated = match counter{
+            0 => format!("{}.{}", path.display(), timestamp),
+            _ => format!("{}.{}-{}", path.display(), timestamp, counter),
+        };
+        if !Path::new(&rotated).exists() && !Path::new(&format!("{}.gz", rotated)).exists(){
+            return PathBuf::from(rotated)
+        }
+        counter += 1;
+    }
+}
+
+// (timestamp, counter) of a rotated file name, the plain name order puts <ts>-1.gz before <ts>.gz
+fn rotated_key(path: &Path) -> (String, usize){
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let name = name.strip_suffix(".gz").unwrap_or(&name);
+    let suffix = name.rsplit('.').next().unwrap_or_default();
+    match suffix.split_once('-'){
+        Some((timestamp, counter)) => (timestamp.to_string(), counter.parse().unwrap_or(0)),
+        None => (suffix.to_string(), 0),
+    }
+}
+
+// rotated files of `path`, oldest first
+fn rotated_files(path: &Path) -> io::Result<Vec<PathBuf>>{
+    let dir = match path.parent(){
+        Some(dir) if dir.exists() => dir,
+        _ => return Ok(Vec::new()),
+    };
+    let prefix = format!("{}.", path.file_name().unwrap_or_default().to_string_lossy());
+    let mut ret = Vec::new();
+    for entry in fs::read_dir(dir)?{
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().to_string();
+        // a compressed file replaces its plain version, left until the rename is done
+        if name.starts_with(&prefix) && !dir.join(format!("{}.gz", name)).exists(){
+            ret.push(entry.path());
+        }
+    }
+    ret.sort_by_key(|path| rotated_key(path));
+    Ok(ret)
+}
+
+// written under a hidden name out of rotated_files, queries only see a finished .gz
+fn gzip(path: &Path) -> io::Result<()>{
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    let tmp = path.with_file_name(format!(".{}.gz.tmp", name));
+    let mut input = File::open(path)?;
+    let output = File::create(&tmp)?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    if let Err(e) = io::copy(&mut input, &mut encoder).and_then(|_| encoder.finish()?.sync_all()){
+        fs::remove_file(&tmp).unwrap_or_default();
+        return Err(e)
+    }
+    fs::rename(&tmp, format!("{}.gz", path.display()))?;
+    fs::remove_file(path)
+}
+
+fn prune(path: &Path, retention: usize, max_age: Option<Duration>) -> io::Result<()>{
+    let files = rotated_files(path)?;
+    let excess = files.len().saturating_sub(retention);
+    for (index, file) in files.iter().enumerate(){
+        let expired = match max_age{
+            Some(max_age) => fs::metadata(file)?.modified()?.elapsed().unwrap_or_default() > max_age,
+            None => false,
+        };
+        if index < excess || expired{
+            fs::remove_file(file)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests{
+    use std::{fs, path::{Path, PathBuf}};
+
+    use uuid::Uuid;
+
+    use super::{rotated_key, rotated_files, prune, gzip, read_lines};
+
+    fn dir() -> PathBuf{
+        let dir = std::env::temp_dir().join(format!("unicom-store-{}", Uuid::new_v4()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn names(files: &Vec<PathBuf>) -> Vec<String>{
+        files.iter().map(|file| file.file_name().unwrap().to_string_lossy().to_string()).collect()
+    }
+
+    #[test]
+    fn rotated_key_orders_counters_after_their_second(){
+        assert_eq!(rotated_key(Path::new("/logs/app.log.20240101120000.gz")), ("20240101120000".to_string(), 0));
+        assert_eq!(rotated_key(Path::new("/logs/app.log.20240101120000-2")), ("20240101120000".to_string(), 2));
+        assert!(rotated_key(Path::new("app.log.20240101120000-10.gz")) > rotated_key(Path::new("app.log.20240101120000-9.gz")));
+    }
+
+    #[test]
+    fn rotated_files_are_sorted_oldest_first(){
+        let dir = dir();
+        for name in ["app.log", "app.log.20240102000000", "app.log.20240101000000-1.gz", "app.log.20240101000000.gz", "other.log.20240101000000"]{
+            fs::write(dir.join(name), "line\n").unwrap();
+        }
+        let files = rotated_files(&dir.join("app.log")).unwrap();
+        assert_eq!(names(&files), vec!["app.log.20240101000000.gz", "app.log.20240101000000-1.gz", "app.log.20240102000000"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn prune_keeps_the_newest_files(){
+        let dir = dir();
+        for second in 1..=5{
+            fs::write(dir.join(format!("app.log.2024010100000{}", second)), "line\n").unwrap();
+        }
+        prune(&dir.join("app.log"), 2, None).unwrap();
+        assert_eq!(names(&rotated_files(&dir.join("app.log")).unwrap()), vec!["app.log.20240101000004", "app.log.20240101000005"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn gzip_replaces_the_plain_file(){
+        let dir = dir();
+        let rotated = dir.join("app.log.20240101000000");
+        fs::write(&rotated, "first\nsecond\n").unwrap();
+        gzip(&rotated).unwrap();
+        let files = rotated_files(&dir.join("app.log")).unwrap();
+        assert_eq!(names(&files), vec!["app.log.20240101000000.gz"]);
+        assert_eq!(read_lines(&files[0]).unwrap(), vec!["first", "second"]);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,144 @@
+use std::{fmt::Display, time::Duration};
+
+use hyper::{Body, Response, StatusCode, header::{CONTENT_TYPE, CACHE_CONTROL}};
+use serde_json::{Map, Value};
+use tokio::{sync::broadcast::{Receiver, error::RecvError}, time::interval};
+use unicom_lib::error::UnicomError;
+
+use super::{LogRecord, query::LogMatcher};
+
+// a record as published to the live tail subscribers
+#[derive(Debug, Clone, Serialize)]
+pub struct LogEvent{
+    // app, unicom or http
+    pub source: String,
+    pub app: Option<String>,
+    #[serde(flatten)]
+    pub record: LogRecord,
+}
+
+impl Display for LogEvent{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let name = self.app.as_deref().unwrap_or(&self.source);
+        write!(f, "[{}]{}|[{:?}]{}", self.record.time, name, self.record.level, self.record.message)
+    }
+}
+
+pub struct LogFilter{
+    sources: Vec<String>,
+    apps: Vec<String>,
+    matcher: LogMatcher,
+}
+
+impl LogFilter{
+    // `source` and `app` take comma separated lists, everything is followed when empty
+    pub fn from_parameters(parameters: &Map<String, Value>) -> Result<LogFilter, UnicomError>{
+        let list = |name: &str| -> Vec<String>{
+            match parameters.get(name){
+                Some(Value::Array(values)) => values.iter().filter_map(|value| value.as_str()).map(|value| value.to_string()).collect(),
+                Some(Value::String(values)) => values.split(',').map(|value| value.trim()).filter(|value| !value.is_empty()).map(|value| value.to_string()).collect(),
+                _ => Vec::new(),
+            }
+        };
+
+        Ok(LogFilter{
+            sources: list("source"),
+            apps: list("app"),
+            matcher: LogMatcher::from_parameters(parameters)?,
+        })
+    }
+
+    pub fn matches(&self, event: &LogEvent) -> bool{
+        if self.sources.len() > 0 && !self.sources.contains(&event.source){
+            return false
+        }
+        if self.apps.len() > 0 && !event.app.as_ref().map(|app| self.apps.contains(app)).unwrap_or(false){
+            return false
+        }
+        self.matcher.matches(&event.record)
+    }
+}
+
+// server-sent events, one json event per record until the client goes away
+pub fn sse_response(mut events: Receiver<LogEvent>, filter: LogFilter) -> Response<Body>{
+    let (mut sender, body) = Body::channel();
+    tokio::spawn(async move{
+        let mut keep_alive = interval(Duration::from_secs(15));
+        loop{
+            let data = tokio::select! {
+                event = events.recv() => match event{
+                    Ok(event) if filter.matches(&event) => format!("data: {}\n\n", serde_json::to_string(&event).unwrap_or_default()),
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(count)) => format!("event: lagged\ndata: {}\n\n", count),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = keep_alive.tick() => ": keep-alive\n\n".to_string(),
+            };
+            if sender.send_data(data.into()).await.is_err(){
+                break
+            }
+        }
+    });
+
+    let mut response = Response::builder().status(StatusCode::OK).body(body).unwrap();
+    response.headers_mut().insert(CONTENT_TYPE, "text/event-stream".parse().unwrap());
+    response.headers_mut().insert(CACHE_CONTROL, "no-cache".parse().unwrap());
+    response
+}
+
+#[cfg(test)]
+mod tests{
+    use serde_json::{json, Map, Value};
+
+    use super::{LogEvent, LogFilter, LogRecord};
+    use crate::log::LogLevel;
+
+    fn event(source: &str, app: Option<&str>, level: LogLevel, message: &str) -> LogEvent{
+        LogEvent{
+            source: source.to_string(),
+            app: app.map(|app| app.to_string()),
+            record: LogRecord{
+                time: "2024-01-01T00:00:00+00:00".to_string(),
+                stream: "stdout".to_string(),
+                level,
+                message: message.to_string(),
+                fields: Map::new(),
+                seq: 1,
+            },
+        }
+    }
+
+    fn filter(parameters: Value) -> LogFilter{
+        LogFilter::from_parameters(parameters.as_object().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn empty_filter_follows_everything(){
+        assert!(filter(json!({})).matches(&event("http", None, LogLevel::Trace, "GET /")));
+    }
+
+    #[test]
+    fn sources_and_apps_take_lists(){
+        let apps = filter(json!({"app": "media, books"}));
+        assert!(apps.matches(&event("app", Some("books"), LogLevel::Info, "ready")));
+        assert!(!apps.matches(&event("app", Some("music"), LogLevel::Info, "ready")));
+        assert!(!apps.matches(&event("unicom", None, LogLevel::Info, "ready")));
+
+        let sources = filter(json!({"source": ["unicom", "http"]}));
+        assert!(sources.matches(&event("http", None, LogLevel::Info, "GET /")));
+        assert!(!sources.matches(&event("app", Some("media"), LogLevel::Info, "ready")));
+    }
+
+    #[test]
+    fn level_and_search_narrow_the_records(){
+        let filter = filter(json!({"level": "WARN", "search": "Disk"}));
+        assert!(filter.matches(&event("app", Some("media"), LogLevel::Error, "disk full")));
+        assert!(!filter.matches(&event("app", Some("media"), LogLevel::Info, "disk full")));
+        assert!(!filter.matches(&event("app", Some("media"), LogLevel::Error, "network down")));
+    }
+
+    #[test]
+    fn invalid_regex_is_refused(){
+        assert!(LogFilter::from_parameters(json!({"regex": "("}).as_object().unwrap()).is_err());
+    }
+}
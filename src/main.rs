@@ -2,7 +2,7 @@ use std::{fs, sync::Arc};
 
 use tokio::sync::Notify;
 
-use crate::log::Logger;
+use crate::log::{Logger, LogLevel};
 
 #[macro_use]
 extern crate serde_derive;
@@ -18,9 +18,14 @@ mod server;
 mod http;
 mod app;
 mod log;
+mod config;
+mod control;
+mod scheduler;
 
 use unicom_lib::config::Config;
 
+use crate::config::DaemonConfig;
+
 lazy_static! {
     static ref LOGGER: Logger = Logger::new();
 }
@@ -29,14 +34,28 @@ lazy_static! {
     static ref SERVER: server::Server = {
         let config = read_config();
         if let Ok(_) = fs::remove_file(&config.unix_stream_path){
-            println!("remove stream ");
+            LOGGER.log_now(LogLevel::Debug, "server", "remove stream".to_string());
         }
         server::Server::new(&config)
     };
 }
 
+lazy_static! {
+    static ref DAEMON_CONFIG: DaemonConfig = read_daemon_config();
+}
+
 #[tokio::main]
 async fn main(){
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.len() > 0{
+        std::process::exit(control::client::run(args).await);
+    }
+
+    if let Err(e) = DAEMON_CONFIG.validate(){
+        eprintln!("config error {:?}", e);
+        std::process::exit(1);
+    }
+
     let close_notify = Arc::new(Notify::new());
     let close_notify_clone = close_notify.clone();
     tokio::spawn(async move {
@@ -46,7 +65,7 @@ async fn main(){
             
     let config = read_config();
     if let Ok(_) = fs::remove_file(&config.unix_stream_path){
-        println!("remove stream ");
+        LOGGER.debug("server", "remove stream".to_string()).await;
     }
 
     SERVER.run().await;
@@ -56,13 +75,20 @@ async fn main(){
     SERVER.stop().await;
 }
 
-pub fn read_config() -> Config{
-    let content = if std::path::Path::new("./config.toml").exists(){
+fn read_config_file() -> String{
+    if std::path::Path::new("./config.toml").exists(){
         std::fs::read_to_string("./config.toml").unwrap()
     }
     else{
         std::fs::read_to_string("/etc/unicom/config.toml").unwrap()
-    };
-    toml::from_str(&content).unwrap()
+    }
+}
+
+pub fn read_config() -> Config{
+    toml::from_str(&read_config_file()).unwrap()
     
+}
+
+pub fn read_daemon_config() -> DaemonConfig{
+    toml::from_str(&read_config_file()).unwrap()
 }
\ No newline at end of file
@@ -0,0 +1,322 @@
+use std::{sync::{Arc, atomic::{AtomicBool, Ordering}}, collections::VecDeque, str::FromStr, time::{Duration, Instant}, process::Stdio};
+
+use chrono::{Local, DateTime};
+use cron::Schedule;
+use serde_json::{Map, Value};
+use tokio::{sync::Mutex, time::{sleep, timeout}, process::Command};
+use unicom_lib::{error::{UnicomError, UnicomErrorKind}, node::api::MethodKind};
+
+use crate::{LOGGER, SERVER};
+
+// kept in the job status, longer output is cut
+const RESULT_MAX_LEN: usize = 4096;
+const HISTORY_LEN: usize = 20;
+
+#[derive(Debug, Deserialize, Clone)]
+pub struct JobConfig{
+    pub name: String,
+    // cron expression with seconds "0 30 3 * * *", the 5 fields crontab form runs at second 0
+    pub schedule: String,
+    // node api to call, the app node for app jobs
+    pub api: Option<String>,
+    // required for daemon jobs calling an api
+    pub node: Option<String>,
+    pub method: Option<MethodKind>,
+    pub parameters: Option<Map<String, Value>>,
+    // shell command run in the app directory, with the app user and environment for app jobs
+    pub command: Option<String>,
+    // daemon jobs only, working directory of the command
+    pub dir: Option<String>,
+    // seconds before the run is abandoned
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct JobRun{
+    // "schedule" or "manual"
+    pub trigger: String,
+    pub time: String,
+    // milliseconds
+    pub duration: u64,
+    pub success: bool,
+    pub result: Option<String>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct JobStatus{
+    id: String,
+    app: Option<String>,
+    schedule: String,
+    next: Option<String>,
+    running: bool,
+    last: Option<JobRun>,
+    history: Vec<JobRun>,
+}
+
+pub struct Job{
+    // "<app>.<name>" for app jobs, the name for daemon jobs
+    pub id: String,
+    app: Option<String>,
+    config: JobConfig,
+    schedule: Schedule,
+    running: AtomicBool,
+    history: Mutex<VecDeque<JobRun>>,
+}
+
+impl Job{
+    fn new(app: Option<&str>, config: &JobConfig) -> Result<Job, UnicomError>{
+        if config.api.is_some() == config.command.is_some(){
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("job {} needs either an api or a command", config.name)))
+        }
+        if app.is_none() && config.api.is_some() && config.node.is_none(){
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("job {} needs a node", config.name)))
+        }
+        let expression = match config.schedule.split_whitespace().count(){
+            5 => format!("0 {}", config.schedule),
+            _ => config.schedule.clone(),
+        };
+        let schedule = Schedule::from_str(&expression)
+            .map_err(|e| UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("job {} schedule {}: {}", config.name, config.schedule, e)))?;
+
+        Ok(Job{
+            id: match app{
+                Some(app) => format!("{}.{}", app, config.name),
+                None => config.name.clone(),
+            },
+            app: app.map(|app| app.to_string()),
+            config: config.clone(),
+            schedule,
+            running: AtomicBool::new(false),
+            history: Mutex::new(VecDeque::new()),
+        })
+    }
+
+    fn is_due(&self, last: &DateTime<Local>, now: &DateTime<Local>) -> bool{
+        match self.schedule.after(last).next(){
+            Some(next) => next <= *now,
+            None => false,
+        }
+    }
+
+    pub async fn status(&self) -> JobStatus{
+        let history: Vec<JobRun> = self.history.lock().await.iter().cloned().collect();
+        JobStatus{
+            id: self.id.clone(),
+            app: self.app.clone(),
+            schedule: self.config.schedule.clone(),
+            next: self.schedule.upcoming(Local).next().map(|next| next.to_rfc3339()),
+            running: self.running.load(Ordering::SeqCst),
+            last: history.last().cloned(),
+            history,
+        }
+    }
+
+    // a run while the previous one is not finished is skipped and recorded as failed
+    pub async fn execute(self: Arc<Self>, trigger: &str) -> JobRun{
+        let time = Local::now().to_rfc3339();
+        let begin = Instant::now();
+        let result = match self.running.swap(true, Ordering::SeqCst){
+            true => Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("job {} previous run still running", self.id))),
+            false => {
+                let result = match timeout(Duration::from_secs(self.config.timeout), self.run()).await{
+                    Ok(result) => result,
+                    Err(_) => Err(UnicomError::new(UnicomErrorKind::NotFound, &format!("job {} timed out after {}s", self.id, self.config.timeout))),
+                };
+                self.running.store(false, Ordering::SeqCst);
+                result
+            },
+        };
+
+        let run = JobRun{
+            trigger: trigger.to_string(),
+            time,
+            duration: begin.elapsed().as_millis() as u64,
+            success: result.is_ok(),
+            result: result.as_ref().ok().cloned(),
+            error: result.as_ref().err().map(|e| format!("{:?}", e)),
+        };
+        // daemon jobs go to the daemon log, an app stream named scheduler would mix with a real app
+        match (&self.app, &run.error){
+            (Some(app), None) => LOGGER.app_stdout(app, format!("[JOB {}] done in {}ms", self.config.name, run.duration)).await,
+            (Some(app), Some(error)) => LOGGER.app_stderr(app, format!("[JOB {}] failed: {}", self.config.name, error)).await,
+            (None, None) => LOGGER.info("scheduler", format!("job {} done in {}ms", self.config.name, run.duration)).await,
+            (None, Some(error)) => LOGGER.warn("scheduler", format!("job {} failed: {}", self.config.name, error)).await,
+        }
+
+        let mut history = self.history.lock().await;
+        history.push_back(run.clone());
+        while history.len() > HISTORY_LEN{
+            history.pop_front();
+        }
+        run
+    }
+
+    async fn run(&self) -> Result<String, UnicomError>{
+        if let Some(api) = &self.config.api{
+            let node_name = match (&self.config.node, &self.app){
+                (Some(node), _) => node,
+                (None, Some(app)) => app,
+                (None, None) => return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, &format!("job {} needs a node", self.id))),
+            };
+            let node = SERVER.controller.node(node_name).await?;
+            let api = node.api(api)?;
+            let method = self.config.method.clone().unwrap_or(MethodKind::GET);
+            let response = node.request(api, method, self.config.parameters.clone().unwrap_or_default()).await?;
+            return Ok(truncate(String::from_utf8_lossy(&response.data).to_string()))
+        }
+
+        let command = self.config.command.clone().unwrap_or_default();
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c").arg(&command);
+        match &self.app{
+            Some(app) => SERVER.controller.apps.prepare_command(app, &mut cmd).await?,
+            None => {
+                if let Some(dir) = &self.config.dir{
+                    cmd.current_dir(dir);
+                }
+            },
+        }
+        let output = cmd.stdin(Stdio::null()).kill_on_drop(true).output().await?;
+        let mut result = String::from_utf8_lossy(&output.stdout).to_string();
+        result.push_str(&String::from_utf8_lossy(&output.stderr));
+        if !output.status.success(){
+            return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, 
+                        &format!("job {} command exited with {}: {}", self.id, output.status, truncate(result))))
+        }
+        Ok(truncate(result))
+    }
+}
+
+pub struct Scheduler{
+    jobs: Arc<Mutex<Vec<Arc<Job>>>>,
+}
+
+impl Scheduler{
+    pub fn new() -> Scheduler{
+        Scheduler{
+            jobs: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    // registers the daemon jobs and starts the clock
+    pub async fn run(&self, configs: &Vec<JobConfig>){
+        for config in configs{
+            match Job::new(None, config){
+                Ok(job) => self.jobs.lock().await.push(Arc::new(job)),
+                Err(e) => LOGGER.error("scheduler", e).await,
+            }
+        }
+
+        let jobs = self.jobs.clone();
+        tokio::spawn(async move{
+            let mut last = Local::now();
+            loop{
+                sleep(Duration::from_secs(1)).await;
+                let now = Local::now();
+                for job in &*jobs.lock().await{
+                    if job.is_due(&last, &now){
+                        let job = job.clone();
+                        tokio::spawn(async move{
+                            job.execute("schedule").await;
+                        });
+                    }
+                }
+                last = now;
+            }
+        });
+    }
+
+    // replaces the jobs of an app, an empty list removes them
+    pub async fn set_app_jobs(&self, app: &str, configs: &Vec<JobConfig>){
+        let mut jobs = self.jobs.lock().await;
+        jobs.retain(|job| job.app.as_deref() != Some(app));
+        for config in configs{
+            match Job::new(Some(app), config){
+                Ok(job) => jobs.push(Arc::new(job)),
+                Err(e) => LOGGER.error(&format!("app {} scheduler", app), e).await,
+            }
+        }
+    }
+
+    pub async fn status(&self) -> Vec<JobStatus>{
+        let mut ret = Vec::new();
+        for job in &*self.jobs.lock().await{
+            ret.push(job.status().await);
+        }
+        ret
+    }
+
+    pub async fn trigger(&self, id: &str) -> Result<JobRun, UnicomError>{
+        let job = self.jobs.lock().await.iter().find(|job| job.id == id).cloned();
+        match job{
+            Some(job) => Ok(job.execute("manual").await),
+            None => Err(UnicomError::new(UnicomErrorKind::NotFound, &format!("job not found {}", id))),
+        }
+    }
+}
+
+fn truncate(mut value: String) -> String{
+    if value.len() > RESULT_MAX_LEN{
+        let mut end = RESULT_MAX_LEN;
+        while !value.is_char_boundary(end){
+            end -= 1;
+        }
+        value.truncate(end);
+    }
+    value
+}
+
+fn default_timeout() -> u64{
+    3600
+}
+
+#[cfg(test)]
+mod tests{
+    use chrono::{Local, TimeZone, Timelike};
+
+    use super::{Job, JobConfig, truncate, RESULT_MAX_LEN};
+
+    fn job(schedule: &str) -> Result<Job, String>{
+        let config: JobConfig = toml::from_str(&format!("name = \"backup\"\nschedule = \"{}\"\ncommand = \"true\"", schedule)).unwrap();
+        Job::new(Some("app"), &config).map_err(|e| format!("{:?}", e))
+    }
+
+    #[test]
+    fn crontab_form_runs_at_second_zero(){
+        let job = job("30 3 * * *").unwrap();
+        assert_eq!(job.id, "app.backup");
+        let next = job.schedule.after(&Local.ymd(2024, 1, 1).and_hms(0, 0, 0)).next().unwrap();
+        assert_eq!((next.hour(), next.minute(), next.second()), (3, 30, 0));
+    }
+
+    #[test]
+    fn seconds_form_is_kept(){
+        let job = job("15 */10 * * * *").unwrap();
+        let last = Local.ymd(2024, 1, 1).and_hms(0, 0, 0);
+        assert!(!job.is_due(&last, &Local.ymd(2024, 1, 1).and_hms(0, 0, 14)));
+        assert!(job.is_due(&last, &Local.ymd(2024, 1, 1).and_hms(0, 0, 15)));
+    }
+
+    #[test]
+    fn invalid_schedules_are_rejected(){
+        assert!(job("every day").is_err());
+        assert!(job("61 * * * *").is_err());
+    }
+
+    #[test]
+    fn job_needs_either_api_or_command(){
+        let config: JobConfig = toml::from_str("name = \"both\"\nschedule = \"* * * * *\"\napi = \"sync\"\ncommand = \"true\"").unwrap();
+        assert!(Job::new(Some("app"), &config).is_err());
+        let config: JobConfig = toml::from_str("name = \"sync\"\nschedule = \"* * * * *\"\napi = \"sync\"").unwrap();
+        assert!(Job::new(None, &config).is_err());
+        assert!(Job::new(Some("app"), &config).is_ok());
+    }
+
+    #[test]
+    fn truncate_keeps_char_boundaries(){
+        let value = format!("{}é", "a".repeat(RESULT_MAX_LEN - 1));
+        assert_eq!(truncate(value).len(), RESULT_MAX_LEN - 1);
+    }
+}
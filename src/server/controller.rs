@@ -3,7 +3,7 @@ use std::sync::Arc;
 use tokio::sync::Mutex;
 use unicom_lib::{node::{Node, NodeConnector}, config::Config, error::{UnicomError, UnicomErrorKind}};
 
-use crate::{http::{router::Router, render::Render, session::SessionManager}, app::AppControler};
+use crate::{http::{self, router::Router, render::Render, session::SessionManager, csrf::{CsrfGuard, CSRF_EXEMPT_TAG, tag_exempt}, roles::{RoleManager, AccessRules}}, app::AppControler, scheduler::Scheduler, LOGGER, DAEMON_CONFIG};
 
 pub struct Controller{
     nodes:  Mutex<Vec<Arc<Node>>>,
@@ -11,6 +11,9 @@ pub struct Controller{
     pub render: Render,
     pub apps: AppControler,
     pub sessions: SessionManager,
+    pub csrf: CsrfGuard,
+    pub access: AccessRules,
+    pub scheduler: Scheduler,
     pub framwork_path: String,
 }
 
@@ -20,8 +23,11 @@ impl Controller{
             nodes: Mutex::new(Vec::new()),
             router: Router::new(),
             render: Render::new(&config.template_dir),
-            apps: AppControler::new(&config.app_dir, &config.unix_stream_path),
-            sessions: SessionManager::new(&config.session_path),
+            apps: AppControler::new(&config.app_dir, &config.unix_stream_path, &DAEMON_CONFIG.app_state_path, &DAEMON_CONFIG.app_archive_dir),
+            sessions: SessionManager::new(&config.session_path, &DAEMON_CONFIG.sessions, RoleManager::new(&DAEMON_CONFIG.roles)),
+            csrf: CsrfGuard::new(&DAEMON_CONFIG.csrf),
+            access: AccessRules::new(&DAEMON_CONFIG.access),
+            scheduler: Scheduler::new(),
             framwork_path: config.framwork_path.clone(),
         }
     }
@@ -34,20 +40,24 @@ impl Controller{
                 None => break,
             };
             if let Err(e) = node.quit().await{
-                println!("quit error {}:{:?}", node.name, e);
+                LOGGER.error(&format!("quit node {}", node.name), e).await;
             };
         }
-        self.apps.close().await
+        self.apps.close().await;
+        self.sessions.flush().await
     }
 
     pub async fn new_node(&self, connector:  Arc<dyn NodeConnector>)-> Result<Arc<Node>, UnicomError>{
         let mut nodes = self.nodes.lock().await;
         let config = connector.init().await?;
-        println!("new node : {:?}", &config);
+        http::check_reserved(&config)?;
+        LOGGER.debug("server", format!("new node : {:?}", &config)).await;
         let node = Node::new(&config, connector).await?;
         
         nodes.push(Arc::new(node));
 
+        // replaces the routes registered for an on demand app
+        self.router.remove_routes(&config.name).await;
         self.router.add(&config).await?;
         self.render.add(&config).await?;
 
@@ -58,12 +68,44 @@ impl Controller{
     }
 
     pub async fn node(&self, name: &str) -> Result<Arc<Node>, UnicomError>{
+        if let Some(node) = self.connected_node(name).await{
+            self.apps.touch(name).await;
+            return Ok(node)
+        }
+        // an on demand app is started and the request held until its node connects
+        if self.apps.demand(name).await?{
+            if let Some(node) = self.connected_node(name).await{
+                return Ok(node)
+            }
+        }
+        Err(UnicomError::new(UnicomErrorKind::NotFound, &format!("node NOT FOUND")))
+    }
+
+    // does not start nor keep alive an on demand app
+    pub async fn connected_node(&self, name: &str) -> Option<Arc<Node>>{
         for node in &*self.nodes.lock().await{
             if node.name == name{
-                return Ok(node.clone());
+                return Some(node.clone());
             }
         }
-        Err(UnicomError::new(UnicomErrorKind::NotFound, &format!("node NOT FOUND")))
+        None
+    }
+
+    // endpoints opt out of the csrf check through the csrf_exempt tag of their node
+    pub async fn csrf_exempt(&self, path: &str) -> bool{
+        let node_name = match self.router.find(path).await{
+            Ok((_, node_name, _)) => node_name,
+            Err(_) => return false,
+        };
+        // no demand start, a forged request must not start an app nor reset its idle timer,
+        // endpoints of a stoped on demand app are exempted through the csrf.exempt config
+        match self.connected_node(&node_name).await{
+            Some(node) => match node.get_tag(CSRF_EXEMPT_TAG).await{
+                Some(tag) => tag_exempt(&tag, path),
+                None => false,
+            },
+            None => false,
+        }
     }
 
     pub async fn get_node_name(&self) -> Vec<String>{
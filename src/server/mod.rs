@@ -1,24 +1,29 @@
 use std::{sync::Arc, convert::Infallible, net::SocketAddr, path::Path, time::Duration};
 
 use futures::future::join_all;
-use hyper::{service::{make_service_fn, service_fn}, Request, Body, Response, StatusCode, header::{HeaderValue, SET_COOKIE}};
-use serde_json::{Map, Value};
+use hyper::{service::{make_service_fn, service_fn}, Request, Body, Response, StatusCode, header::{HeaderValue, SET_COOKIE, USER_AGENT}, server::conn::AddrStream};
+use serde_json::{Map, Value, json};
 use tera::Context;
 use tokio::{net::UnixListener, time::{Instant, sleep}};
-use unicom_lib::{error::UnicomError, config::Config, node::{endpoint::{EndPointKind, ApiConfig}, api::MethodKind, message::{response::UnicomResponse, UnicomMessage, request::UnicomRequest}, NodeConnector, Node}};
+use unicom_lib::{error::{UnicomError, UnicomErrorKind}, config::Config, node::{endpoint::{EndPointKind, ApiConfig}, api::MethodKind, message::{response::UnicomResponse, UnicomMessage, request::UnicomRequest}, NodeConnector, Node}};
 
 
-use crate::{http::{self, input_file::InputFile, session::Session, add_http}, unix::UnixConnector, system::controller::SystemConnector, LOGGER};
+use crate::{http::{self, csrf, input_file::InputFile, session::Session, add_http, CALLER_PARAMETER}, unix::UnixConnector, system::controller::SystemConnector, control::control_server, log::tail::{LogFilter, sse_response}, LOGGER, DAEMON_CONFIG};
 
 use self::controller::Controller;
 
 
 pub mod controller;
 
+// live tail of the logs as server-sent events, needs the logs.view permission
+const LOG_TAIL_PATH: &str = "/_unicom/logs/tail";
+// set by the app health checks, a request without session cookie then gets a transient session
+pub const HEALTH_PROBE_HEADER: &str = "x-unicom-health-probe";
+
 pub struct Server{
     unix_stream_path: String,
     pub controller: Arc<Controller>,
-    server_addr: SocketAddr,
+    pub server_addr: SocketAddr,
 }
 
 impl Server{
@@ -35,6 +40,8 @@ impl Server{
         tokio::spawn(Server::new_node(Arc::new(SystemConnector{ controller: self.controller.clone() }), self.controller.clone()));
         tokio::spawn(Server::unix_server(self.unix_stream_path.clone(), self.controller.clone()));
         tokio::spawn(Server::http_server(self.server_addr, self.controller.clone()));
+        tokio::spawn(control_server(DAEMON_CONFIG.control_path.clone(), self.controller.clone()));
+        self.controller.scheduler.run(&DAEMON_CONFIG.jobs).await;
         sleep(Duration::from_secs_f32(1.0)).await;
         if let Err(e) = self.controller.apps.init().await{
             LOGGER.error("apps init error", e).await;
@@ -49,14 +56,16 @@ impl Server{
         if let Err(e) = controller.sessions.load().await{
             LOGGER.error("error load session", e).await;
         }
+        controller.sessions.run();
 
-        let make_service = make_service_fn(move |_conn| {
+        let make_service = make_service_fn(move |conn: &AddrStream| {
             let controller = controller.clone();
+            let remote_addr = conn.remote_addr();
             async move {
                 Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
                     let controller = controller.clone();
                     async move {
-                        Ok::<_, Infallible>(Server::http_worker(controller, req).await)
+                        Ok::<_, Infallible>(Server::http_worker(controller, req, remote_addr).await)
                     }
                 }))
             }
@@ -65,10 +74,16 @@ impl Server{
         hyper::Server::bind(&server_addr).serve(make_service).await.unwrap();
     }
 
-    async fn http_worker(controller: Arc<Controller>, request: Request<Body>) -> Response<Body>{
+    async fn http_worker(controller: Arc<Controller>, request: Request<Body>, remote_addr: SocketAddr) -> Response<Body>{
         let mut cookie: Option<String> = None;
+        let mut transient = false;
         let session = match controller.sessions.parse_session(&request).await{
             Some(session) => session,
+            // a check every interval would otherwise store a new session each time
+            None if request.headers().contains_key(HEALTH_PROBE_HEADER) => {
+                transient = true;
+                controller.sessions.transient()
+            },
             None => {
                 let session = controller.sessions.create().await;
                 cookie = Some(session.gen_cookies());
@@ -76,6 +91,11 @@ impl Server{
             },
         }; 
 
+        let user_agent = request.headers().get(USER_AGENT).and_then(|value| value.to_str().ok()).map(|value| value.to_string());
+        if !transient && controller.sessions.touch(&session, Some(remote_addr.ip().to_string()), user_agent).await{
+            cookie = Some(session.gen_cookies());
+        }
+
         let path = request.uri().path().to_string();
         let method = request.method().clone();
         let start = Instant::now();
@@ -98,6 +118,16 @@ impl Server{
 
     async fn http_request(controller: Arc<Controller>, request: Request<Body>, session: Arc<Session>) -> Result<Response<Body>, UnicomError>{
         let (parts, body) = request.into_parts();
+        controller.access.verify(parts.uri.path(), &session)?;
+        let endpoint_exempt = !csrf::is_safe_method(&parts.method) && controller.csrf_exempt(parts.uri.path()).await;
+        let body = controller.csrf.verify(&parts, body, &session, endpoint_exempt).await?;
+        if parts.uri.path() == LOG_TAIL_PATH{
+            if !session.has_permission("logs.view"){
+                return Err(UnicomError::new(UnicomErrorKind::NotAllowed, "permission logs.view required"))
+            }
+            let filter = LogFilter::from_parameters(&http::parse_parameters(&parts)?)?;
+            return Ok(sse_response(LOGGER.subscribe(), filter))
+        }
         let (endpoint,node_name, url_var) = controller.router.find(parts.uri.path()).await?;
         match endpoint {
             EndPointKind::Static { path } => {
@@ -120,7 +150,7 @@ impl Server{
                 let mut param = http::parse_parameters(&parts)?;
                 let node = controller.node(&node_name).await?;
                 let api = node.api(&api)?;
-                add_http(api.get_method(&method)?, &mut param, url_var, &session, http::parse_body(&parts, body).await?);
+                add_http(api.get_method(&method)?, &mut param, url_var, &session, http::parse_body(&parts, body).await?, &node_name, &node_name);
                 let resp = node.request(api, method, param).await?;
                 let file: InputFile = serde_json::from_str(&String::from_utf8(resp.data)?)?;
                 Ok(hyper_staticfile::ResponseBuilder::new()
@@ -134,7 +164,7 @@ impl Server{
                 let mut param = http::parse_parameters(&parts)?;
                 let node = controller.node(&node_name).await?;
                 let api = node.api(&api)?;
-                add_http(api.get_method(&method)?, &mut param, url_var, &session, http::parse_body(&parts, body).await?);
+                add_http(api.get_method(&method)?, &mut param, url_var, &session, http::parse_body(&parts, body).await?, &node_name, &node_name);
                 let node_resp = node.request(api, method, param).await?;
                 let string = String::from_utf8(node_resp.data)?;
                 let mut resp = Response::builder().status(StatusCode::OK).body(Body::from(string)).unwrap();
@@ -161,7 +191,7 @@ impl Server{
                         c_param.extend(d_param);
                     }
 
-                    futures.push(Server::execute_node(key, controller.clone(), config, c_method, c_param, &url_var, &session, parsed_body.clone()));
+                    futures.push(Server::execute_node(key, controller.clone(), config, c_method, c_param, &url_var, &session, parsed_body.clone(), &node_name));
                 }
     
                 for result in join_all(futures).await{
@@ -171,6 +201,7 @@ impl Server{
                 }
                 context.insert("source_node", &node_name);
                 context.insert("user", &session.get_user());
+                context.insert("csrf_token", &session.csrf_token);
     
                 let output = controller.render.render(&template, &context).await?;
                 
@@ -183,11 +214,11 @@ impl Server{
 
     async fn execute_node(key: &str, controller: Arc<Controller>, config: &ApiConfig, method: MethodKind,  
                             mut param: Map<String, Value>, url_var: &Vec<String>, session: &Arc<Session>,
-                            parsed_body: Box<Option<Value>>) -> Result<(String, UnicomResponse), UnicomError>{
+                            parsed_body: Box<Option<Value>>, caller: &str) -> Result<(String, UnicomResponse), UnicomError>{
         let node = controller.node(&config.node).await?;
         let api = node.api(&config.api)?;
         
-        add_http(api.get_method(&method)?, &mut param, url_var.clone(), session, *parsed_body);
+        add_http(api.get_method(&method)?, &mut param, url_var.clone(), session, *parsed_body, &config.node, caller);
 
         Ok((key.to_string(), node.request(api, method, param).await?))
     }
@@ -242,11 +273,11 @@ impl Server{
     }
 
     async fn transaction_node(node: Arc<Node>, controller: Arc<Controller>, request_id: u64, request: UnicomRequest){
-        println!("new transaction {:?}", request);
+        LOGGER.trace("server", format!("new transaction {:?}", request)).await;
         let target_node = match controller.node(&request.node_name).await{
             Ok(target_node) => target_node,
             Err(e) => {
-                println!("get node erreur {:?}",e);
+                LOGGER.warn("server", format!("get node erreur {:?}", e)).await;
                 node.error(request_id, e).await.unwrap_or_default();
                 return
             },
@@ -255,22 +286,25 @@ impl Server{
         let api = match target_node.api(&request.name){
             Ok(api) => api,
             Err(e) => {
-                println!("get api erreur {:?}",e);
+                LOGGER.warn("server", format!("get api erreur {:?}", e)).await;
                 node.error(request_id, e).await.unwrap_or_default();
                 return
             },
         };
 
-        match target_node.request(api, request.method, request.parameters).await{
+        let mut parameters = request.parameters;
+        parameters.insert(CALLER_PARAMETER.to_string(), json!(node.name));
+
+        match target_node.request(api, request.method, parameters).await{
             Ok(response) => {
                 if let Err(e) = node.response(request_id, response.data).await{
-                    println!("send node response erreur {:?}",e);
+                    LOGGER.warn("server", format!("send node response erreur {:?}", e)).await;
                     return
                 }
             },
 
             Err(e) => {
-                println!("request node erreur {:?}",e);
+                LOGGER.warn("server", format!("request node erreur {:?}", e)).await;
                 node.error(request_id, e).await.unwrap_or_default();
                 return
             },
@@ -1,11 +1,11 @@
 use std::{sync::Arc, time::Duration, env, path::Path};
 
 use async_trait::async_trait;
-use serde_json::json;
+use serde_json::{json, Value};
 use tokio::time::sleep;
-use unicom_lib::{node::{NodeConnector, NodeConfig, api::{ApiMethod, MethodKind, Parameter, ValueKind}, message::{request::UnicomRequest, response::UnicomResponse, UnicomMessage}}, error::UnicomError, config::Manifest};
+use unicom_lib::{node::{NodeConnector, NodeConfig, api::{ApiMethod, MethodKind, Parameter, ValueKind}, message::{request::UnicomRequest, response::UnicomResponse, UnicomMessage}}, error::{UnicomError, UnicomErrorKind}, config::Manifest};
 
-use crate::{server::controller::Controller, LOGGER};
+use crate::{server::controller::Controller, log::query::LogQuery, http::CALLER_PARAMETER, LOGGER};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct LoginInput{
@@ -17,6 +17,17 @@ pub struct SystemConnector{
     pub controller: Arc<Controller>,
 }
 
+impl SystemConnector{
+    // the session of the request must hold the permission
+    async fn require(&self, request: &UnicomRequest, permission: &str) -> Result<(), UnicomError>{
+        let session_id = request.parameters.get("session_id").and_then(|session_id| session_id.as_str()).unwrap_or("");
+        if !self.controller.sessions.has_permission(session_id, permission).await?{
+            return Err(UnicomError::new(UnicomErrorKind::NotAllowed, &format!("permission {} required", permission)))
+        }
+        Ok(())
+    }
+}
+
 #[async_trait]
 impl NodeConnector for SystemConnector{
 
@@ -47,9 +58,67 @@ impl NodeConnector for SystemConnector{
             Parameter::new("session_id", ValueKind::SessionID, true),
             Parameter::new("input", ValueKind::Input, true)])]);
         config.add_api(5, "app_log", vec![ApiMethod::new(MethodKind::GET, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true),
             Parameter::new("name", ValueKind::String, true)])]);
         config.add_api(6, "app_update", vec![ApiMethod::new(MethodKind::GET, vec![
             Parameter::new("name", ValueKind::String, true)])]);
+        // the namespace is the name of the calling node, given by the daemon in the reserved caller parameter
+        config.add_api(7, "session_data", vec![
+            ApiMethod::new(MethodKind::GET, vec![
+                Parameter::new("session_id", ValueKind::SessionID, true),
+                Parameter::new(CALLER_PARAMETER, ValueKind::String, true),
+                Parameter::new("key", ValueKind::String, false)]),
+            ApiMethod::new(MethodKind::POST, vec![
+                Parameter::new("session_id", ValueKind::SessionID, true),
+                Parameter::new(CALLER_PARAMETER, ValueKind::String, true),
+                Parameter::new("key", ValueKind::String, true),
+                Parameter::new("input", ValueKind::Input, true)])]);
+        config.add_api(8, "sessions", vec![ApiMethod::new(MethodKind::GET, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true),
+            Parameter::new("user", ValueKind::String, false)])]);
+        config.add_api(9, "session_revoke", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true),
+            Parameter::new("target", ValueKind::String, true)])]);
+        config.add_api(10, "session_revoke_user", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true),
+            Parameter::new("user", ValueKind::String, true)])]);
+        config.add_api(11, "logout", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true)])]);
+        config.add_api(12, "permissions", vec![ApiMethod::new(MethodKind::GET, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true),
+            Parameter::new("permission", ValueKind::String, false)])]);
+        config.add_api(13, "app_start", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("name", ValueKind::String, true)])]);
+        config.add_api(14, "app_restart", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("name", ValueKind::String, true)])]);
+        config.add_api(15, "app_enable", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("name", ValueKind::String, true)])]);
+        config.add_api(16, "app_disable", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("name", ValueKind::String, true)])]);
+        config.add_api(17, "app_install", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true),
+            Parameter::new("path", ValueKind::String, true)])]);
+        config.add_api(18, "app_uninstall", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true),
+            Parameter::new("name", ValueKind::String, true),
+            Parameter::new("delete", ValueKind::String, false)])]);
+        config.add_api(19, "jobs", vec![ApiMethod::new(MethodKind::GET, vec![])]);
+        config.add_api(20, "job_run", vec![ApiMethod::new(MethodKind::POST, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true),
+            Parameter::new("id", ValueKind::String, true)])]);
+        config.add_api(21, "logs", vec![ApiMethod::new(MethodKind::GET, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true),
+            Parameter::new("source", ValueKind::String, false),
+            Parameter::new("app", ValueKind::String, false),
+            Parameter::new("level", ValueKind::String, false),
+            Parameter::new("since", ValueKind::String, false),
+            Parameter::new("until", ValueKind::String, false),
+            Parameter::new("search", ValueKind::String, false),
+            Parameter::new("regex", ValueKind::String, false),
+            Parameter::new("limit", ValueKind::String, false),
+            Parameter::new("cursor", ValueKind::String, false)])]);
+        config.add_api(22, "log_metrics", vec![ApiMethod::new(MethodKind::GET, vec![
+            Parameter::new("session_id", ValueKind::SessionID, true)])]);
 
         Ok(config)
     }
@@ -80,13 +149,103 @@ impl NodeConnector for SystemConnector{
                 UnicomResponse::from_json(&json!(self.controller.sessions.authentication(session_id, &input.login, &input.password).await?))
             }
             5 =>{
+                self.require(&request, "logs.view").await?;
                 let name = request.parameters.get("name").unwrap().as_str().unwrap_or("");
-                UnicomResponse::from_json(&json!(LOGGER.logs.lock().await.get_log(name)))
+                UnicomResponse::from_json(&json!(LOGGER.get_log(name).await?))
             }
             6 =>{
                 let name = request.parameters.get("name").unwrap().as_str().unwrap_or("");
                 UnicomResponse::from_json(&json!(self.controller.apps.update(name).await?))
             }
+            7 =>{
+                let session_id = request.parameters.get("session_id").unwrap().as_str().unwrap_or("");
+                let namespace = match request.parameters.get(CALLER_PARAMETER).and_then(|caller| caller.as_str()){
+                    Some(caller) if caller.len() > 0 => caller,
+                    _ => return Err(UnicomError::new(UnicomErrorKind::ParameterInvalid, "session data needs the calling node")),
+                };
+                let key = request.parameters.get("key").and_then(|key| key.as_str());
+                match request.method{
+                    MethodKind::POST => {
+                        let value = request.parameters.get("input").cloned().unwrap_or(Value::Null);
+                        UnicomResponse::from_json(&json!(self.controller.sessions.set_data(session_id, namespace, key.unwrap_or(""), value).await?))
+                    },
+                    _ => UnicomResponse::from_json(&self.controller.sessions.get_data(session_id, namespace, key).await?),
+                }
+            }
+            8 =>{
+                let session_id = request.parameters.get("session_id").unwrap().as_str().unwrap_or("");
+                let user = request.parameters.get("user").and_then(|user| user.as_str());
+                UnicomResponse::from_json(&json!(self.controller.sessions.list(session_id, user).await?))
+            }
+            9 =>{
+                let session_id = request.parameters.get("session_id").unwrap().as_str().unwrap_or("");
+                let target = request.parameters.get("target").unwrap().as_str().unwrap_or("");
+                UnicomResponse::from_json(&json!(self.controller.sessions.revoke(session_id, target).await?))
+            }
+            10 =>{
+                let session_id = request.parameters.get("session_id").unwrap().as_str().unwrap_or("");
+                let user = request.parameters.get("user").unwrap().as_str().unwrap_or("");
+                UnicomResponse::from_json(&json!(self.controller.sessions.revoke_user(session_id, user).await?))
+            }
+            11 =>{
+                let session_id = request.parameters.get("session_id").unwrap().as_str().unwrap_or("");
+                UnicomResponse::from_json(&json!(self.controller.sessions.logout(session_id).await?))
+            }
+            12 =>{
+                let session_id = request.parameters.get("session_id").unwrap().as_str().unwrap_or("");
+                match request.parameters.get("permission").and_then(|permission| permission.as_str()){
+                    Some(permission) => UnicomResponse::from_json(&json!(self.controller.sessions.has_permission(session_id, permission).await?)),
+                    None => UnicomResponse::from_json(&json!(self.controller.sessions.permissions(session_id).await?)),
+                }
+            }
+            13 =>{
+                let name = request.parameters.get("name").unwrap().as_str().unwrap_or("");
+                UnicomResponse::from_json(&json!(self.controller.apps.start(name).await?))
+            }
+            14 =>{
+                let name = request.parameters.get("name").unwrap().as_str().unwrap_or("");
+                UnicomResponse::from_json(&json!(self.controller.apps.restart(name).await?))
+            }
+            15 =>{
+                let name = request.parameters.get("name").unwrap().as_str().unwrap_or("");
+                UnicomResponse::from_json(&json!(self.controller.apps.enable(name).await?))
+            }
+            16 =>{
+                let name = request.parameters.get("name").unwrap().as_str().unwrap_or("");
+                UnicomResponse::from_json(&json!(self.controller.apps.disable(name).await?))
+            }
+            17 =>{
+                self.require(&request, "apps.manage").await?;
+                let path = request.parameters.get("path").unwrap().as_str().unwrap_or("");
+                UnicomResponse::from_json(&json!(self.controller.apps.install(path).await?))
+            }
+            18 =>{
+                self.require(&request, "apps.manage").await?;
+                let name = request.parameters.get("name").unwrap().as_str().unwrap_or("");
+                let delete = match request.parameters.get("delete"){
+                    Some(Value::Bool(delete)) => *delete,
+                    Some(Value::String(delete)) => delete == "true",
+                    _ => false,
+                };
+                UnicomResponse::from_json(&json!(self.controller.apps.uninstall(name, delete).await?))
+            }
+            19 =>{
+                UnicomResponse::from_json(&json!(self.controller.scheduler.status().await))
+            }
+            20 =>{
+                self.require(&request, "jobs.run").await?;
+                let id = request.parameters.get("id").unwrap().as_str().unwrap_or("");
+                UnicomResponse::from_json(&json!(self.controller.scheduler.trigger(id).await?))
+            }
+            21 =>{
+                self.require(&request, "logs.view").await?;
+                let query = LogQuery::from_parameters(&request.parameters)?;
+                UnicomResponse::from_json(&json!(LOGGER.query(query).await?))
+            }
+            22 =>{
+                self.require(&request, "logs.view").await?;
+                UnicomResponse::from_json(&json!(LOGGER.metrics()))
+            }
             _ => Ok(UnicomResponse::empty())
         }
         
@@ -95,7 +254,7 @@ impl NodeConnector for SystemConnector{
         Ok(())//todo!()
     }
     async fn error(&self, request_id: u64, error: UnicomError) -> Result<(), UnicomError>{
-        println!("système node config error {}{:?}", request_id, error);
+        LOGGER.warn("system", format!("système node config error {}{:?}", request_id, error)).await;
         Ok(())
     }
     async fn next(&self) -> Result<UnicomMessage, UnicomError>{
@@ -104,7 +263,7 @@ impl NodeConnector for SystemConnector{
         }
     }
     async fn quit(&self) -> Result<(), UnicomError>{
-        println!("système node quit");
+        LOGGER.info("system", "système node quit".to_string()).await;
         Ok(())
     }
 